@@ -1,92 +1,779 @@
-pub fn decode_bencoded_value(encoded_value: &str) -> (serde_json::Value, &str) {
-    let (tag, mut rest) = encoded_value.split_at(1);
-    let tag_char = tag.chars().next().expect("Tag doesn't exist");
-    match tag_char {
-        'i' => {
-            // i52e
-            if let Some((s, remainder)) = rest.split_once('e') {
-                if let Ok(n) = s.parse::<i64>() {
-                    return (n.into(), remainder);
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use thiserror::Error;
+
+/// Lists and dictionaries may not be nested deeper than this, so that hostile input
+/// can't exhaust the stack.
+const MAX_DEPTH: usize = 256;
+
+/// A generic bencoded value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BencodeValue {
+    /// `i<integer>e`
+    Int(i64),
+    /// `<length>:<bytes>`, not necessarily valid UTF-8.
+    Bytes(Vec<u8>),
+    /// `l<values>e`
+    List(Vec<BencodeValue>),
+    /// `d<key><value>...e`, keys are byte strings kept in sorted order.
+    Dict(BTreeMap<Vec<u8>, BencodeValue>),
+}
+
+impl BencodeValue {
+    /// Encodes the value in canonical form: dictionary keys sorted, integers without leading
+    /// zeros or negative zero.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_to(&mut out);
+        out
+    }
+
+    pub fn encode_to(&self, out: &mut Vec<u8>) {
+        match self {
+            BencodeValue::Int(n) => {
+                out.push(b'i');
+                out.extend_from_slice(n.to_string().as_bytes());
+                out.push(b'e');
+            }
+            BencodeValue::Bytes(bytes) => encode_bytes(bytes, out),
+            BencodeValue::List(values) => {
+                out.push(b'l');
+                for value in values {
+                    value.encode_to(out);
+                }
+                out.push(b'e');
+            }
+            BencodeValue::Dict(dict) => {
+                out.push(b'd');
+                for (key, value) in dict {
+                    encode_bytes(key, out);
+                    value.encode_to(out);
                 }
+                out.push(b'e');
             }
         }
-        'd' => {
-            // d3:foo3:bar5:helloi52ee
-            // d2:xxld3:foo3:bar5:helloi52e3:aaa7:fkslwerei-433e3:asdee
-            let mut dict = serde_json::Map::new();
-            while !rest.is_empty() && !rest.starts_with('e') {
-                let (key, remainder) = decode_bencoded_value(rest);
-                let (value, remainder) = decode_bencoded_value(remainder);
-                let key = match key {
-                    serde_json::Value::String(k) => k,
-                    k => {
-                        panic!("dict keys must be strings, not {:?}", k);
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            BencodeValue::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            BencodeValue::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    /// Returns the byte string if it is valid UTF-8.
+    pub fn as_str(&self) -> Option<&str> {
+        self.as_bytes().and_then(|b| std::str::from_utf8(b).ok())
+    }
+
+    pub fn as_list(&self) -> Option<&[BencodeValue]> {
+        match self {
+            BencodeValue::List(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<Vec<u8>, BencodeValue>> {
+        match self {
+            BencodeValue::Dict(dict) => Some(dict),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict_mut(&mut self) -> Option<&mut BTreeMap<Vec<u8>, BencodeValue>> {
+        match self {
+            BencodeValue::Dict(dict) => Some(dict),
+            _ => None,
+        }
+    }
+
+    /// Looks up `key` if this is a dictionary.
+    pub fn get(&self, key: &[u8]) -> Option<&BencodeValue> {
+        self.as_dict().and_then(|dict| dict.get(key))
+    }
+
+    /// Converts the value into JSON for display, byte strings are decoded lossily as UTF-8.
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            BencodeValue::Int(n) => (*n).into(),
+            BencodeValue::Bytes(bytes) => String::from_utf8_lossy(bytes).into(),
+            BencodeValue::List(values) => values.iter().map(Self::to_json).collect(),
+            BencodeValue::Dict(dict) => dict
+                .iter()
+                .map(|(k, v)| (String::from_utf8_lossy(k).into_owned(), v.to_json()))
+                .collect::<serde_json::Map<_, _>>()
+                .into(),
+        }
+    }
+}
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(bytes.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend_from_slice(bytes);
+}
+
+impl From<i64> for BencodeValue {
+    fn from(n: i64) -> Self {
+        BencodeValue::Int(n)
+    }
+}
+
+impl From<&str> for BencodeValue {
+    fn from(s: &str) -> Self {
+        BencodeValue::Bytes(s.as_bytes().to_vec())
+    }
+}
+
+impl From<Vec<u8>> for BencodeValue {
+    fn from(bytes: Vec<u8>) -> Self {
+        BencodeValue::Bytes(bytes)
+    }
+}
+
+impl From<Vec<BencodeValue>> for BencodeValue {
+    fn from(values: Vec<BencodeValue>) -> Self {
+        BencodeValue::List(values)
+    }
+}
+
+impl From<BTreeMap<Vec<u8>, BencodeValue>> for BencodeValue {
+    fn from(dict: BTreeMap<Vec<u8>, BencodeValue>) -> Self {
+        BencodeValue::Dict(dict)
+    }
+}
+
+/// Error produced when decoding malformed bencode.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("{kind} at byte {offset}")]
+pub struct DecodeError {
+    /// Byte offset into the input at which the problem was detected.
+    pub offset: usize,
+    pub kind: DecodeErrorKind,
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum DecodeErrorKind {
+    #[error("unexpected end of input")]
+    UnexpectedEof,
+    #[error("unexpected byte {0:#04x}")]
+    UnexpectedByte(u8),
+    #[error("number has leading zeros")]
+    LeadingZeros,
+    #[error("negative zero")]
+    NegativeZero,
+    #[error("missing digits")]
+    MissingDigits,
+    #[error("integer does not fit in 64 bits")]
+    IntegerOverflow,
+    #[error("string length does not fit in memory")]
+    LengthOverflow,
+    #[error("dictionary keys are not sorted")]
+    UnsortedKeys,
+    #[error("dictionary key appears twice")]
+    DuplicateKey,
+    #[error("values are nested too deeply")]
+    TooDeep,
+    #[error("trailing data after value")]
+    TrailingData,
+}
+
+/// Decodes a single bencoded value spanning the whole of `input`.
+pub fn decode(input: &[u8]) -> Result<BencodeValue, DecodeError> {
+    let mut decoder = Decoder::new(input);
+    let value = decoder.decode_value()?;
+    decoder.finish()?;
+    Ok(value)
+}
+
+/// Decodes a single value like [`decode`], but accepts dictionary keys in any order.
+///
+/// Many trackers and torrent authoring tools don't sort keys, which is harmless when reading
+/// their output. Keys that appear twice are still rejected.
+pub fn decode_lenient(input: &[u8]) -> Result<BencodeValue, DecodeError> {
+    let mut decoder = Decoder::lenient(input);
+    let value = decoder.decode_value()?;
+    decoder.finish()?;
+    Ok(value)
+}
+
+/// Decodes one value from the front of `input` and returns it along with the unconsumed bytes.
+pub fn decode_prefix(input: &[u8]) -> Result<(BencodeValue, &[u8]), DecodeError> {
+    let mut decoder = Decoder::new(input);
+    let value = decoder.decode_value()?;
+    Ok((value, decoder.remaining()))
+}
+
+/// Finds the exact byte range of the value stored under `key` in the top-level dictionary.
+///
+/// This lets callers hash or copy a nested value without re-encoding it. Unlike [`decode`],
+/// dictionary keys may appear in any order, as they do in files written by lax encoders, and
+/// the first value stored under `key` wins.
+pub fn dict_value_span(input: &[u8], key: &[u8]) -> Result<Option<Range<usize>>, DecodeError> {
+    let mut decoder = Decoder::new(input);
+    if decoder.peek()? != b'd' {
+        return Err(decoder.error(DecodeErrorKind::UnexpectedByte(decoder.peek()?)));
+    }
+    decoder.enter()?;
+    let mut span = None;
+    while decoder.peek()? != b'e' {
+        let k = decoder.bytes()?;
+        let start = decoder.position();
+        decoder.skip_value()?;
+        if k == key && span.is_none() {
+            span = Some(start..decoder.position());
+        }
+    }
+    decoder.leave();
+    decoder.finish()?;
+    Ok(span)
+}
+
+/// Strict bencode decoder over a byte slice.
+pub struct Decoder<'a> {
+    input: &'a [u8],
+    pos: usize,
+    depth: usize,
+    /// Whether dictionary keys must be sorted.
+    sorted_keys: bool,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(input: &'a [u8]) -> Self {
+        Self {
+            input,
+            pos: 0,
+            depth: 0,
+            sorted_keys: true,
+        }
+    }
+
+    /// A decoder that accepts dictionary keys in any order, see [`decode_lenient`].
+    pub fn lenient(input: &'a [u8]) -> Self {
+        Self {
+            sorted_keys: false,
+            ..Self::new(input)
+        }
+    }
+
+    /// Offset of the next byte to be read.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub fn remaining(&self) -> &'a [u8] {
+        &self.input[self.pos..]
+    }
+
+    /// Fails if any input is left over.
+    pub fn finish(self) -> Result<(), DecodeError> {
+        if self.pos != self.input.len() {
+            return Err(self.error(DecodeErrorKind::TrailingData));
+        }
+        Ok(())
+    }
+
+    pub fn decode_value(&mut self) -> Result<BencodeValue, DecodeError> {
+        match self.peek()? {
+            b'i' => {
+                self.pos += 1;
+                let n = self.integer(b'e')?;
+                Ok(BencodeValue::Int(n))
+            }
+            b'0'..=b'9' => self.bytes().map(|b| BencodeValue::Bytes(b.to_vec())),
+            b'l' => {
+                self.enter()?;
+                let mut values = Vec::new();
+                while self.peek()? != b'e' {
+                    values.push(self.decode_value()?);
+                }
+                self.leave();
+                Ok(BencodeValue::List(values))
+            }
+            b'd' => {
+                self.enter()?;
+                let mut dict = BTreeMap::new();
+                let mut last_key: Option<&[u8]> = None;
+                while self.peek()? != b'e' {
+                    let key_offset = self.pos;
+                    let key = self.bytes()?;
+                    let kind = if self.sorted_keys {
+                        last_key
+                            .is_some_and(|last| last >= key)
+                            .then_some(DecodeErrorKind::UnsortedKeys)
+                    } else {
+                        dict.contains_key(key)
+                            .then_some(DecodeErrorKind::DuplicateKey)
+                    };
+                    if let Some(kind) = kind {
+                        return Err(DecodeError {
+                            offset: key_offset,
+                            kind,
+                        });
                     }
-                };
-                rest = remainder;
-                dict.insert(key, value);
+                    last_key = Some(key);
+                    let value = self.decode_value()?;
+                    dict.insert(key.to_vec(), value);
+                }
+                self.leave();
+                Ok(BencodeValue::Dict(dict))
+            }
+            b => Err(self.error(DecodeErrorKind::UnexpectedByte(b))),
+        }
+    }
+
+    /// Steps over one value without building it or checking the order of dictionary keys.
+    fn skip_value(&mut self) -> Result<(), DecodeError> {
+        match self.peek()? {
+            b'i' => {
+                self.pos += 1;
+                self.integer(b'e')?;
+            }
+            b'0'..=b'9' => {
+                self.bytes()?;
             }
-            return (dict.into(), &rest[1..]);
-        }
-        'l' => {
-            // l<bencoded_elements>e
-            // l5:helloi52ee
-            let mut values = Vec::new();
-            while !rest.is_empty() && !rest.starts_with('e') {
-                let (v, remainder) = decode_bencoded_value(rest);
-                rest = remainder;
-                values.push(v);
+            b'l' => {
+                self.enter()?;
+                while self.peek()? != b'e' {
+                    self.skip_value()?;
+                }
+                self.leave();
             }
-            return (values.into(), &rest[1..]);
-        }
-        '0'..='9' => {
-            if let Some((str, rest)) = rest.split_once(':').and_then(|(_, remainder)| {
-                tag.parse::<usize>()
-                    .ok()
-                    .map(|len| (remainder[..len].to_string(), &remainder[len..]))
-            }) {
-                return (str.into(), rest);
+            b'd' => {
+                self.enter()?;
+                while self.peek()? != b'e' {
+                    self.bytes()?;
+                    self.skip_value()?;
+                }
+                self.leave();
             }
+            b => return Err(self.error(DecodeErrorKind::UnexpectedByte(b))),
         }
-        _ => {}
+        Ok(())
+    }
+
+    fn error(&self, kind: DecodeErrorKind) -> DecodeError {
+        DecodeError {
+            offset: self.pos,
+            kind,
+        }
+    }
+
+    fn peek(&self) -> Result<u8, DecodeError> {
+        self.input
+            .get(self.pos)
+            .copied()
+            .ok_or_else(|| self.error(DecodeErrorKind::UnexpectedEof))
     }
 
-    panic!("unhandled encoded value: {}", encoded_value);
+    /// Consumes the opening tag of a list or dictionary.
+    fn enter(&mut self) -> Result<(), DecodeError> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.error(DecodeErrorKind::TooDeep));
+        }
+        self.depth += 1;
+        self.pos += 1;
+        Ok(())
+    }
+
+    /// Consumes the closing `e` of a list or dictionary.
+    fn leave(&mut self) {
+        self.depth -= 1;
+        self.pos += 1;
+    }
+
+    /// Reads an optionally negative decimal number terminated by `end`.
+    fn integer(&mut self, end: u8) -> Result<i64, DecodeError> {
+        let start = self.pos;
+        let negative = self.peek()? == b'-';
+        if negative {
+            self.pos += 1;
+        }
+        let digits_start = self.pos;
+        let mut n: i64 = 0;
+        loop {
+            match self.peek()? {
+                b @ b'0'..=b'9' => {
+                    let digit = i64::from(b - b'0');
+                    n = n
+                        .checked_mul(10)
+                        .and_then(|n| {
+                            if negative {
+                                n.checked_sub(digit)
+                            } else {
+                                n.checked_add(digit)
+                            }
+                        })
+                        .ok_or(DecodeError {
+                            offset: start,
+                            kind: DecodeErrorKind::IntegerOverflow,
+                        })?;
+                    self.pos += 1;
+                }
+                b if b == end => break,
+                b => return Err(self.error(DecodeErrorKind::UnexpectedByte(b))),
+            }
+        }
+        let digits = &self.input[digits_start..self.pos];
+        let kind = match digits {
+            [] => Some(DecodeErrorKind::MissingDigits),
+            [b'0'] if negative => Some(DecodeErrorKind::NegativeZero),
+            [b'0', _, ..] => Some(DecodeErrorKind::LeadingZeros),
+            _ => None,
+        };
+        if let Some(kind) = kind {
+            return Err(DecodeError {
+                offset: start,
+                kind,
+            });
+        }
+        // Skip the terminator.
+        self.pos += 1;
+        Ok(n)
+    }
+
+    /// Reads a `<length>:<bytes>` string.
+    fn bytes(&mut self) -> Result<&'a [u8], DecodeError> {
+        let start = self.pos;
+        if !self.peek()?.is_ascii_digit() {
+            return Err(self.error(DecodeErrorKind::UnexpectedByte(self.peek()?)));
+        }
+        let length = self.integer(b':').map_err(|e| match e.kind {
+            DecodeErrorKind::IntegerOverflow => DecodeError {
+                offset: start,
+                kind: DecodeErrorKind::LengthOverflow,
+            },
+            _ => e,
+        })?;
+        let length = usize::try_from(length).map_err(|_| DecodeError {
+            offset: start,
+            kind: DecodeErrorKind::LengthOverflow,
+        })?;
+        if length > self.input.len() - self.pos {
+            return Err(DecodeError {
+                offset: self.input.len(),
+                kind: DecodeErrorKind::UnexpectedEof,
+            });
+        }
+        let bytes = &self.input[self.pos..self.pos + length];
+        self.pos += length;
+        Ok(bytes)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::random::Rng;
+
+    fn bytes(s: &str) -> BencodeValue {
+        BencodeValue::Bytes(s.as_bytes().to_vec())
+    }
+
+    fn error(input: &str) -> DecodeError {
+        decode(input.as_bytes()).expect_err("input should be rejected")
+    }
 
     #[test]
     fn decode_dictionary() {
-        let mut expected = serde_json::Map::new();
-        expected.insert("foo".into(), "bar".into());
-        expected.insert("hello".into(), 52.into());
+        let mut expected = BTreeMap::new();
+        expected.insert(b"foo".to_vec(), bytes("bar"));
+        expected.insert(b"hello".to_vec(), BencodeValue::Int(52));
         assert_eq!(
-            (expected.into(), ""),
-            decode_bencoded_value("d3:foo3:bar5:helloi52ee")
+            Ok(BencodeValue::Dict(expected)),
+            decode(b"d3:foo3:bar5:helloi52ee")
         );
     }
 
     #[test]
     fn decode_list() {
         assert_eq!(
-            (
-                serde_json::Value::Array(vec!["hello".into(), 52.into()]),
-                ""
-            ),
-            decode_bencoded_value("l5:helloi52ee")
+            Ok(BencodeValue::List(vec![
+                bytes("hello"),
+                BencodeValue::Int(52)
+            ])),
+            decode(b"l5:helloi52ee")
         );
     }
 
     #[test]
     fn decode_string() {
-        assert_eq!(("hello".into(), ""), decode_bencoded_value("5:hello"));
+        assert_eq!(Ok(bytes("hello")), decode(b"5:hello"));
+        assert_eq!(Ok(bytes("")), decode(b"0:"));
+    }
+
+    #[test]
+    fn decode_binary_string() {
+        assert_eq!(
+            Ok(BencodeValue::Bytes(vec![0xe8, 0x76, 0x00, 0xff])),
+            decode(b"4:\xe8\x76\x00\xff")
+        );
     }
 
     #[test]
     fn decode_integer() {
-        assert_eq!((52.into(), ""), decode_bencoded_value("i52e"));
+        assert_eq!(Ok(BencodeValue::Int(52)), decode(b"i52e"));
+        assert_eq!(Ok(BencodeValue::Int(0)), decode(b"i0e"));
+        assert_eq!(Ok(BencodeValue::Int(-42)), decode(b"i-42e"));
+        assert_eq!(
+            Ok(BencodeValue::Int(i64::MIN)),
+            decode(b"i-9223372036854775808e")
+        );
+    }
+
+    #[test]
+    fn decode_prefix_returns_rest() {
+        assert_eq!(
+            Ok((BencodeValue::Int(1), &b"4:spam"[..])),
+            decode_prefix(b"i1e4:spam")
+        );
+    }
+
+    #[test]
+    fn to_json() {
+        let value = decode(b"d3:foold1:ai-1eee5:hello5:worlde").unwrap();
+        assert_eq!(
+            serde_json::json!({"foo": [{"a": -1}], "hello": "world"}),
+            value.to_json()
+        );
+    }
+
+    #[test]
+    fn encode_values() {
+        assert_eq!(b"i52e".to_vec(), BencodeValue::Int(52).encode());
+        assert_eq!(b"i-3e".to_vec(), BencodeValue::Int(-3).encode());
+        assert_eq!(b"i0e".to_vec(), BencodeValue::Int(0).encode());
+        assert_eq!(b"5:hello".to_vec(), bytes("hello").encode());
+        assert_eq!(
+            b"l5:helloi52ee".to_vec(),
+            BencodeValue::List(vec![bytes("hello"), 52.into()]).encode()
+        );
+    }
+
+    #[test]
+    fn encode_sorts_dictionary_keys() {
+        let mut dict = BTreeMap::new();
+        dict.insert(b"zz".to_vec(), BencodeValue::Int(1));
+        dict.insert(b"a".to_vec(), BencodeValue::Int(2));
+        dict.insert(b"ab".to_vec(), BencodeValue::Int(3));
+        assert_eq!(
+            b"d1:ai2e2:abi3e2:zzi1ee".to_vec(),
+            BencodeValue::Dict(dict).encode()
+        );
+    }
+
+    #[test]
+    fn rewrite_preserves_untouched_values() {
+        let input = b"d8:announce3:old4:infod6:lengthi1e4:name1:a7:privatei1eee";
+        let mut value = decode(input).unwrap();
+        value
+            .as_dict_mut()
+            .unwrap()
+            .insert(b"announce".to_vec(), "new".into());
+        assert_eq!(
+            b"d8:announce3:new4:infod6:lengthi1e4:name1:a7:privatei1eee".to_vec(),
+            value.encode()
+        );
+    }
+
+    fn random_bytes(rng: &mut Rng) -> Vec<u8> {
+        let len = rng.below(12);
+        (0..len).map(|_| rng.next_u32() as u8).collect()
+    }
+
+    fn random_value(rng: &mut Rng, depth: usize) -> BencodeValue {
+        let kinds = if depth == 0 { 2 } else { 4 };
+        match rng.below(kinds) {
+            0 => BencodeValue::Int(match rng.below(4) {
+                0 => i64::MIN,
+                1 => i64::MAX,
+                _ => rng.next_u64() as i64 >> rng.below(64),
+            }),
+            1 => BencodeValue::Bytes(random_bytes(rng)),
+            2 => {
+                let len = rng.below(5);
+                BencodeValue::List((0..len).map(|_| random_value(rng, depth - 1)).collect())
+            }
+            _ => {
+                let len = rng.below(5);
+                BencodeValue::Dict(
+                    (0..len)
+                        .map(|_| (random_bytes(rng), random_value(rng, depth - 1)))
+                        .collect(),
+                )
+            }
+        }
+    }
+
+    #[test]
+    fn decode_encode_round_trip() {
+        let mut rng = Rng::with_seed(0x2545_f491_4f6c_dd1d);
+        for _ in 0..2000 {
+            let value = random_value(&mut rng, 4);
+            let encoded = value.encode();
+            let decoded = decode(&encoded).expect("canonical encoding should decode");
+            assert_eq!(value, decoded);
+            assert_eq!(encoded, decoded.encode());
+        }
+    }
+
+    #[test]
+    fn find_dict_value_span() {
+        let input = b"d8:announce3:url4:infod6:lengthi1eee";
+        let span = dict_value_span(input, b"info").unwrap().unwrap();
+        assert_eq!(b"d6:lengthi1ee", &input[span]);
+        assert_eq!(Ok(None), dict_value_span(input, b"missing"));
+        assert_eq!(
+            DecodeErrorKind::TrailingData,
+            dict_value_span(b"d4:infoi1eee", b"info").unwrap_err().kind
+        );
+
+        // Unsorted keys are located as written, at any depth.
+        let input = b"d4:infod1:bi1e1:ai2ee8:announce3:urle";
+        let span = dict_value_span(input, b"info").unwrap().unwrap();
+        assert_eq!(b"d1:bi1e1:ai2ee", &input[span]);
+        assert_eq!(
+            DecodeErrorKind::UnexpectedEof,
+            dict_value_span(b"d4:infod1:bi1e", b"info")
+                .unwrap_err()
+                .kind
+        );
+    }
+
+    #[test]
+    fn reject_unexpected_eof() {
+        assert_eq!(
+            DecodeError {
+                offset: 0,
+                kind: DecodeErrorKind::UnexpectedEof
+            },
+            error("")
+        );
+        assert_eq!(DecodeErrorKind::UnexpectedEof, error("i52").kind);
+        assert_eq!(DecodeErrorKind::UnexpectedEof, error("l5:hello").kind);
+        assert_eq!(DecodeErrorKind::UnexpectedEof, error("d3:foo").kind);
+        assert_eq!(
+            DecodeError {
+                offset: 6,
+                kind: DecodeErrorKind::UnexpectedEof
+            },
+            error("10:abc")
+        );
+    }
+
+    #[test]
+    fn reject_leading_zeros() {
+        assert_eq!(
+            DecodeError {
+                offset: 1,
+                kind: DecodeErrorKind::LeadingZeros
+            },
+            error("i03e")
+        );
+        assert_eq!(DecodeErrorKind::LeadingZeros, error("i-03e").kind);
+        assert_eq!(DecodeErrorKind::LeadingZeros, error("03:abc").kind);
+    }
+
+    #[test]
+    fn reject_negative_zero() {
+        assert_eq!(
+            DecodeError {
+                offset: 1,
+                kind: DecodeErrorKind::NegativeZero
+            },
+            error("i-0e")
+        );
+    }
+
+    #[test]
+    fn reject_malformed_integers() {
+        assert_eq!(DecodeErrorKind::MissingDigits, error("ie").kind);
+        assert_eq!(DecodeErrorKind::MissingDigits, error("i-e").kind);
+        assert_eq!(DecodeErrorKind::UnexpectedByte(b'x'), error("i1xe").kind);
+        assert_eq!(
+            DecodeErrorKind::IntegerOverflow,
+            error("i9223372036854775808e").kind
+        );
+    }
+
+    #[test]
+    fn reject_length_overflow() {
+        assert_eq!(
+            DecodeError {
+                offset: 0,
+                kind: DecodeErrorKind::LengthOverflow
+            },
+            error("99999999999999999999999:abc")
+        );
+        assert_eq!(DecodeErrorKind::UnexpectedByte(b'-'), error("l-1:ae").kind);
+    }
+
+    #[test]
+    fn reject_unsorted_keys() {
+        assert_eq!(
+            DecodeError {
+                offset: 12,
+                kind: DecodeErrorKind::UnsortedKeys
+            },
+            error("d5:helloi52e3:foo3:bare")
+        );
+        assert_eq!(DecodeErrorKind::UnsortedKeys, error("d1:ai1e1:ai2ee").kind);
+    }
+
+    #[test]
+    fn accept_unsorted_keys_leniently() {
+        let mut expected = BTreeMap::new();
+        expected.insert(b"foo".to_vec(), bytes("bar"));
+        expected.insert(b"hello".to_vec(), BencodeValue::Int(52));
+        assert_eq!(
+            Ok(BencodeValue::Dict(expected)),
+            decode_lenient(b"d5:helloi52e3:foo3:bare")
+        );
+        assert_eq!(
+            DecodeError {
+                offset: 7,
+                kind: DecodeErrorKind::DuplicateKey
+            },
+            decode_lenient(b"d1:ai1e1:ai2ee").unwrap_err()
+        );
+        assert_eq!(
+            DecodeErrorKind::TrailingData,
+            decode_lenient(b"dei1e").unwrap_err().kind
+        );
+    }
+
+    #[test]
+    fn reject_non_string_keys() {
+        assert_eq!(
+            DecodeErrorKind::UnexpectedByte(b'i'),
+            error("di1e3:fooe").kind
+        );
+    }
+
+    #[test]
+    fn reject_trailing_data() {
+        assert_eq!(
+            DecodeError {
+                offset: 4,
+                kind: DecodeErrorKind::TrailingData
+            },
+            error("i52ee")
+        );
+    }
+
+    #[test]
+    fn reject_deep_nesting() {
+        let input = "l".repeat(MAX_DEPTH + 1) + &"e".repeat(MAX_DEPTH + 1);
+        assert_eq!(DecodeErrorKind::TooDeep, error(&input).kind);
+        let input = "l".repeat(MAX_DEPTH) + &"e".repeat(MAX_DEPTH);
+        assert!(decode(input.as_bytes()).is_ok());
     }
 }
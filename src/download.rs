@@ -0,0 +1,639 @@
+//! Downloads whole torrents from the peers the trackers hand out.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures_util::{SinkExt, StreamExt};
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+use tokio::net::TcpStream;
+use tokio::time::{sleep_until, timeout, timeout_at, Instant};
+use tokio_util::codec::Framed;
+
+use crate::error::BittorrentError;
+use crate::peer::{Bitfield, BitfieldError, Handshake, MessageFramer, MessageTag, PeerMessage};
+use crate::peer_id::PeerId;
+use crate::storage::Storage;
+use crate::torrent::{Info, Torrent};
+use crate::tracker::announcer::{Announcer, TransferStats};
+use crate::tracker::TrackerTiers;
+
+pub use self::engine::DownloadConfig;
+pub use self::picker::{Block, PiecePicker};
+pub use self::session::{PeerEvent, PeerSession};
+pub use self::window::RequestWindow;
+
+pub mod engine;
+pub mod picker;
+pub mod session;
+#[cfg(test)]
+mod testing;
+pub mod window;
+
+/// Largest block requested at once, bigger requests are commonly rejected by peers.
+pub const BLOCK_MAX: usize = 1 << 14;
+
+/// Port reported to trackers.
+const PORT: u16 = 6881;
+
+/// How long to wait on a peer at each stage of a connection.
+#[derive(Debug, Clone)]
+pub struct PeerTimeouts {
+    /// Establishing the TCP connection.
+    pub connect: Duration,
+    /// Receiving the peer's handshake once connected.
+    pub handshake: Duration,
+    /// Receiving any of the blocks we requested, after which the peer is considered snubbing
+    /// us. A snubbing peer that misses it again is given up on.
+    pub request: Duration,
+    /// Receiving anything at all, keep-alives included, before the peer is given up on.
+    pub inactivity: Duration,
+    /// Being kept choked, or the peer having nothing we need, before it is given up on to make
+    /// room for other peers.
+    pub idle: Duration,
+    /// Sending nothing before a keep-alive is sent.
+    pub keep_alive: Duration,
+}
+
+impl Default for PeerTimeouts {
+    /// Peers send keep-alives every two minutes, so one silent for longer is gone.
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(10),
+            handshake: Duration::from_secs(10),
+            request: Duration::from_secs(30),
+            inactivity: Duration::from_secs(150),
+            idle: Duration::from_secs(60),
+            keep_alive: Duration::from_secs(90),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum DownloadError {
+    #[error("peer closed the connection")]
+    Disconnected,
+    #[error("peer did not respond in time")]
+    Timeout,
+    #[error("peer sent a malformed {0:?} message")]
+    MalformedMessage(MessageTag),
+    #[error("peer sent an invalid bitfield: {0}")]
+    InvalidBitfield(#[from] BitfieldError),
+    #[error("piece {0} failed hash verification")]
+    HashMismatch(usize),
+    #[error("torrent has no piece {0}")]
+    NoSuchPiece(usize),
+    #[error("no peer could provide piece {0}")]
+    PieceUnavailable(usize),
+    #[error("no peer could provide {missing} of {total} pieces")]
+    Incomplete { missing: usize, total: usize },
+    #[error("no peer connected and no piece was verified for {0:?}")]
+    Stalled(Duration),
+}
+
+/// A connection to a peer, reporting what the peer does as [`PeerEvent`]s.
+pub struct PeerConnection {
+    stream: Framed<TcpStream, MessageFramer>,
+    peer_id: PeerId,
+    session: PeerSession,
+    window: RequestWindow,
+    timeouts: PeerTimeouts,
+    last_sent: Instant,
+    last_received: Instant,
+}
+
+impl PeerConnection {
+    /// Performs the handshake, advertises the pieces we `have`, declares interest and waits to
+    /// be unchoked, for no longer than the idle timeout.
+    pub async fn connect(
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+        peer_id: PeerId,
+        have: &Bitfield,
+        timeouts: PeerTimeouts,
+    ) -> Result<Self, BittorrentError> {
+        let mut stream = timeout(timeouts.connect, TcpStream::connect(addr))
+            .await
+            .map_err(|_| DownloadError::Timeout)??;
+
+        let handshake = Handshake::new(info_hash, peer_id);
+        let theirs = timeout(timeouts.handshake, handshake.exchange(&mut stream))
+            .await
+            .map_err(|_| DownloadError::Timeout)??;
+
+        let mut connection = Self {
+            stream: Framed::new(
+                stream,
+                MessageFramer::for_torrent(have.num_pieces(), BLOCK_MAX),
+            ),
+            peer_id: theirs.peer_id,
+            session: PeerSession::new(have.num_pieces()),
+            window: RequestWindow::default(),
+            timeouts,
+            last_sent: Instant::now(),
+            last_received: Instant::now(),
+        };
+        // Peers that have nothing yet may leave the bitfield out.
+        if have.count() > 0 {
+            connection.send(PeerMessage::Bitfield(have.clone())).await?;
+        }
+        connection.set_interested(true).await?;
+        // Pieces the peer announces meanwhile are tracked by the session.
+        let deadline = Instant::now() + connection.timeouts.idle;
+        while connection.is_choked() {
+            connection.next_event_before(deadline).await?;
+        }
+        Ok(connection)
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    pub fn session(&self) -> &PeerSession {
+        &self.session
+    }
+
+    pub fn request_window(&self) -> &RequestWindow {
+        &self.window
+    }
+
+    pub fn set_request_window(&mut self, window: RequestWindow) {
+        self.window = window;
+    }
+
+    pub fn timeouts(&self) -> &PeerTimeouts {
+        &self.timeouts
+    }
+
+    pub fn has_piece(&self, index: usize) -> bool {
+        self.session.has_piece(index)
+    }
+
+    pub fn is_choked(&self) -> bool {
+        self.session.peer_choking()
+    }
+
+    /// Downloads the piece at `index` and verifies it against its hash.
+    ///
+    /// Requests are pipelined up to the size of the request window, and blocks are accepted in
+    /// whatever order they arrive.
+    pub async fn fetch_piece(
+        &mut self,
+        info: &Info,
+        index: usize,
+    ) -> Result<Vec<u8>, BittorrentError> {
+        let piece_len = info.piece_len(index);
+        let mut piece = vec![0; piece_len];
+        let mut pending: VecDeque<Block> = blocks(piece_len)
+            .map(|range| Block {
+                index,
+                begin: range.start,
+                len: range.len(),
+            })
+            .collect();
+        let mut last_block = Instant::now();
+        // A peer that stops delivering blocks, even if it keeps sending other messages, is
+        // given up on so the piece can go to someone else.
+        let mut last_progress = Instant::now();
+
+        while !pending.is_empty() || !self.session.requests().is_empty() {
+            // With no one else to hand the piece to, a snubbing peer is given up on.
+            let deadline = last_progress + self.timeouts.request;
+            while self.is_choked() {
+                self.next_event_before(deadline).await?;
+            }
+            if self.session.requests().is_empty() {
+                last_block = Instant::now();
+            }
+            while self.session.requests().len() < self.window.size() {
+                let Some(block) = pending.pop_front() else {
+                    break;
+                };
+                self.request(block).await?;
+            }
+
+            match self.next_event_before(deadline).await? {
+                PeerEvent::Choked { dropped } => {
+                    // They are requested again once we are unchoked.
+                    for block in dropped.into_iter().rev() {
+                        pending.push_front(block);
+                    }
+                }
+                PeerEvent::Block { block, data } => {
+                    piece[block.begin..block.begin + block.len].copy_from_slice(&data);
+                    self.window.record(block.len, last_block.elapsed());
+                    last_block = Instant::now();
+                    last_progress = last_block;
+                }
+                _ => {}
+            }
+        }
+
+        let hash: [u8; 20] = Sha1::digest(&piece).into();
+        if hash != info.pieces.0[index] {
+            return Err(DownloadError::HashMismatch(index).into());
+        }
+        Ok(piece)
+    }
+
+    pub async fn set_interested(&mut self, interested: bool) -> Result<(), BittorrentError> {
+        match self.session.set_interested(interested) {
+            Some(message) => self.send(message).await,
+            None => Ok(()),
+        }
+    }
+
+    pub async fn request(&mut self, block: Block) -> Result<(), BittorrentError> {
+        let message = self.session.request(block);
+        self.send(message).await
+    }
+
+    /// Withdraws a request that another peer already fulfilled, if it is still outstanding.
+    pub async fn cancel(&mut self, block: Block) -> Result<(), BittorrentError> {
+        match self.session.cancel(block) {
+            Some(message) => self.send(message).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Waits for the peer to do something of note, sending keep-alives meanwhile.
+    ///
+    /// Fails if the peer stays silent for longer than the inactivity timeout.
+    pub async fn next_event(&mut self) -> Result<PeerEvent, BittorrentError> {
+        loop {
+            let keep_alive = self.last_sent + self.timeouts.keep_alive;
+            let silent = self.last_received + self.timeouts.inactivity;
+            let message = tokio::select! {
+                message = self.stream.next() => message,
+                _ = sleep_until(keep_alive) => {
+                    self.send(PeerMessage::KeepAlive).await?;
+                    continue;
+                }
+                _ = sleep_until(silent) => return Err(DownloadError::Timeout.into()),
+            };
+            let message = message.ok_or(DownloadError::Disconnected)??;
+            self.last_received = Instant::now();
+            if let Some(event) = self.session.handle(message)? {
+                return Ok(event);
+            }
+        }
+    }
+
+    pub async fn next_event_before(
+        &mut self,
+        deadline: Instant,
+    ) -> Result<PeerEvent, BittorrentError> {
+        timeout_at(deadline, self.next_event())
+            .await
+            .map_err(|_| DownloadError::Timeout)?
+    }
+
+    async fn send(&mut self, message: PeerMessage) -> Result<(), BittorrentError> {
+        self.stream.send(message).await?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+}
+
+/// Byte ranges of the blocks a piece of `piece_len` bytes is requested in, only the last one
+/// may be shorter than [`BLOCK_MAX`].
+pub fn blocks(piece_len: usize) -> impl Iterator<Item = Range<usize>> {
+    (0..piece_len)
+        .step_by(BLOCK_MAX)
+        .map(move |begin| begin..piece_len.min(begin + BLOCK_MAX))
+}
+
+/// Fetches the piece at `index` from the first of `peers` able to provide it, verifies it and
+/// writes it to `output`.
+pub async fn download_piece(
+    info: &Info,
+    info_hash: [u8; 20],
+    index: usize,
+    peers: &[SocketAddr],
+    peer_id: PeerId,
+    output: &Path,
+) -> Result<(), BittorrentError> {
+    let num_pieces = info.pieces.0.len();
+    if index >= num_pieces {
+        return Err(DownloadError::NoSuchPiece(index).into());
+    }
+    let have = Bitfield::new(num_pieces);
+    for &addr in peers {
+        let Ok(mut connection) =
+            PeerConnection::connect(addr, info_hash, peer_id, &have, PeerTimeouts::default()).await
+        else {
+            continue;
+        };
+        if !connection.has_piece(index) {
+            continue;
+        }
+        if let Ok(piece) = connection.fetch_piece(info, index).await {
+            std::fs::write(output, piece)?;
+            return Ok(());
+        }
+    }
+    Err(DownloadError::PieceUnavailable(index).into())
+}
+
+/// Downloads every piece of `torrent` into `storage`, announcing to its trackers meanwhile.
+pub async fn download(
+    torrent: &Torrent,
+    storage: &Storage,
+    peer_id: PeerId,
+    config: DownloadConfig,
+) -> Result<(), BittorrentError> {
+    let tiers = torrent.tracker_tiers();
+    if tiers.is_empty() {
+        return Err(BittorrentError::NoTrackers);
+    }
+
+    let info_hash = torrent.info_hash();
+    let stats = Arc::new(TransferStats::new(torrent.info.total_length() as u64));
+    let announcer = Announcer::new(
+        TrackerTiers::new(tiers),
+        info_hash,
+        peer_id,
+        PORT,
+        stats.clone(),
+    );
+    let (announcer, mut peers) = announcer.spawn();
+
+    let result = engine::fetch_pieces(
+        torrent.info.clone(),
+        info_hash,
+        peer_id,
+        storage.clone(),
+        &mut peers,
+        stats,
+        config,
+    )
+    .await;
+    if result.is_ok() {
+        announcer.completed();
+    }
+    announcer.stop().await;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    use super::testing::{data, single_file_torrent, FakeSeeder};
+    use super::*;
+
+    #[test]
+    fn split_pieces_into_blocks() {
+        let blocks = |len| blocks(len).collect::<Vec<_>>();
+        assert_eq!(vec![0..16384, 16384..32768], blocks(32768));
+        assert_eq!(vec![0..16384, 16384..32768, 32768..40000], blocks(40000));
+        assert_eq!(vec![0..1696], blocks(1696));
+        assert_eq!(vec![0..16384], blocks(16384));
+        assert_eq!(vec![0..16384, 16384..16385], blocks(16385));
+        assert!(blocks(0).is_empty());
+    }
+
+    #[tokio::test]
+    async fn download_single_pieces() {
+        // Pieces of 40000 bytes end in a short block, and the final piece is short as well.
+        let data = data(100_000);
+        let info = single_file_torrent(&data, 40_000);
+        let info_hash = [7; 20];
+        let seeder = FakeSeeder::new(&info, info_hash, data.clone())
+            .spawn()
+            .await;
+        let dir = tempfile::tempdir().unwrap();
+
+        for (index, expected) in data.chunks(40_000).enumerate() {
+            let output = dir.path().join(format!("piece-{index}"));
+            download_piece(
+                &info,
+                info_hash,
+                index,
+                &[seeder],
+                PeerId::generate(),
+                &output,
+            )
+            .await
+            .unwrap();
+            assert_eq!(expected, &fs::read(&output).unwrap()[..]);
+        }
+        assert_eq!(20_000, fs::read(dir.path().join("piece-2")).unwrap().len());
+
+        let output = dir.path().join("piece-3");
+        assert!(matches!(
+            download_piece(&info, info_hash, 3, &[seeder], PeerId::generate(), &output).await,
+            Err(BittorrentError::DownloadError(DownloadError::NoSuchPiece(
+                3
+            )))
+        ));
+    }
+
+    #[tokio::test]
+    async fn verify_single_pieces() {
+        let data = data(100_000);
+        let info = single_file_torrent(&data, 32_768);
+        let info_hash = [7; 20];
+        let good = FakeSeeder::new(&info, info_hash, data.clone());
+        let corrupt = FakeSeeder {
+            corrupt: [3].into(),
+            ..good.clone()
+        }
+        .spawn()
+        .await;
+        let lacking = FakeSeeder {
+            has: [0].into(),
+            ..good.clone()
+        }
+        .spawn()
+        .await;
+        let good = good.spawn().await;
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("piece");
+
+        let result = download_piece(
+            &info,
+            info_hash,
+            3,
+            &[corrupt, lacking],
+            PeerId::generate(),
+            &output,
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(BittorrentError::DownloadError(
+                DownloadError::PieceUnavailable(3)
+            ))
+        ));
+        assert!(!output.exists());
+
+        download_piece(
+            &info,
+            info_hash,
+            3,
+            &[corrupt, lacking, good],
+            PeerId::generate(),
+            &output,
+        )
+        .await
+        .unwrap();
+        assert_eq!(&data[3 * 32_768..], &fs::read(&output).unwrap()[..]);
+    }
+
+    #[tokio::test]
+    async fn pipeline_out_of_order_blocks() {
+        // Pieces of 20 blocks, ending in a short one, answered in random order.
+        let data = data(5 * 327_000);
+        let info = single_file_torrent(&data, 327_000);
+        let info_hash = [7; 20];
+        let seeder = FakeSeeder {
+            jitter: Duration::from_millis(5),
+            ..FakeSeeder::new(&info, info_hash, data.clone())
+        }
+        .spawn()
+        .await;
+
+        let have = Bitfield::new(5);
+        let mut connection = PeerConnection::connect(
+            seeder,
+            info_hash,
+            PeerId::generate(),
+            &have,
+            PeerTimeouts::default(),
+        )
+        .await
+        .unwrap();
+        connection.set_request_window(RequestWindow::new(2, 8, Duration::from_secs(3)));
+        for (index, expected) in data.chunks(327_000).enumerate() {
+            assert_eq!(
+                expected,
+                connection.fetch_piece(&info, index).await.unwrap()
+            );
+        }
+        // Loopback delivers far more than 8 blocks in 3 seconds.
+        assert_eq!(8, connection.request_window().size());
+    }
+
+    #[tokio::test]
+    async fn keep_alive_and_time_out_silent_peers() {
+        // Unchokes and then only listens.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (received, mut received_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let theirs = Handshake::read(&mut stream).await.unwrap();
+            let handshake = Handshake::new(theirs.info_hash, PeerId([1; 20]));
+            handshake.write(&mut stream).await.unwrap();
+            let mut stream = Framed::new(stream, MessageFramer::new(BLOCK_MAX));
+            stream.send(PeerMessage::Unchoke).await.unwrap();
+            while let Some(Ok(message)) = stream.next().await {
+                let _ = received.send(message);
+            }
+        });
+
+        let timeouts = PeerTimeouts {
+            inactivity: Duration::from_millis(300),
+            keep_alive: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let have = Bitfield::new(5);
+        let mut connection =
+            PeerConnection::connect(addr, [7; 20], PeerId::generate(), &have, timeouts)
+                .await
+                .unwrap();
+        let start = Instant::now();
+        assert!(matches!(
+            connection.next_event().await,
+            Err(BittorrentError::DownloadError(DownloadError::Timeout))
+        ));
+        assert!(start.elapsed() >= Duration::from_millis(250));
+        drop(connection);
+
+        let mut messages = Vec::new();
+        while let Some(message) = received_rx.recv().await {
+            messages.push(message);
+        }
+        assert_eq!(PeerMessage::Interested, messages[0]);
+        assert!(messages.len() >= 4, "{messages:?}");
+        assert!(messages[1..].iter().all(|m| *m == PeerMessage::KeepAlive));
+    }
+
+    #[tokio::test]
+    async fn time_out_slow_handshakes() {
+        let data = data(100_000);
+        let info = single_file_torrent(&data, 32_768);
+        let seeder = FakeSeeder {
+            latency: Duration::from_millis(500),
+            ..FakeSeeder::new(&info, [7; 20], data)
+        }
+        .spawn()
+        .await;
+
+        let timeouts = PeerTimeouts {
+            handshake: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let have = Bitfield::new(4);
+        let result =
+            PeerConnection::connect(seeder, [7; 20], PeerId::generate(), &have, timeouts).await;
+        assert!(matches!(
+            result,
+            Err(BittorrentError::DownloadError(DownloadError::Timeout))
+        ));
+    }
+
+    /// Compares a single outstanding request with an adaptive window over a link with 10ms of
+    /// latency. Run with `cargo test --release bench_pipelining -- --ignored --nocapture`.
+    #[tokio::test]
+    #[ignore]
+    async fn bench_pipelining() {
+        let data = data(4 << 20);
+        let info = single_file_torrent(&data, 256 << 10);
+        let info_hash = [7; 20];
+        let seeder = FakeSeeder {
+            latency: Duration::from_millis(10),
+            ..FakeSeeder::new(&info, info_hash, data.clone())
+        }
+        .spawn()
+        .await;
+
+        let num_pieces = info.pieces.0.len();
+        let have = Bitfield::new(num_pieces);
+        for (name, window) in [
+            ("1 request", RequestWindow::fixed(1)),
+            ("adaptive", RequestWindow::default()),
+        ] {
+            let mut connection = PeerConnection::connect(
+                seeder,
+                info_hash,
+                PeerId::generate(),
+                &have,
+                PeerTimeouts::default(),
+            )
+            .await
+            .unwrap();
+            connection.set_request_window(window);
+            let start = Instant::now();
+            for index in 0..num_pieces {
+                connection.fetch_piece(&info, index).await.unwrap();
+            }
+            let elapsed = start.elapsed();
+            println!(
+                "{name}: {} bytes in {elapsed:?}, {:.1} MiB/s, window {}",
+                data.len(),
+                data.len() as f64 / elapsed.as_secs_f64() / (1 << 20) as f64,
+                connection.request_window().size()
+            );
+        }
+    }
+}
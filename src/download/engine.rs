@@ -0,0 +1,702 @@
+//! Downloads from many peers at once, handing each of them the blocks the picker chooses.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use sha1::{Digest, Sha1};
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinSet;
+use tokio::time::{sleep_until, Instant};
+
+use super::picker::{Block, PeerKey, PiecePicker, Received};
+use super::{DownloadError, PeerConnection, PeerEvent, PeerTimeouts};
+use crate::error::BittorrentError;
+use crate::peer_id::PeerId;
+use crate::storage::Storage;
+use crate::torrent::Info;
+use crate::tracker::announcer::TransferStats;
+use crate::tracker::PeerInfo;
+
+/// Tunables of the download engine.
+#[derive(Debug, Clone)]
+pub struct DownloadConfig {
+    /// Most peers connected at once, further peers wait until a connection ends.
+    pub max_peers: usize,
+    /// Timeouts of each peer connection. Requests to a peer that doesn't deliver a block within
+    /// the request timeout are handed to other peers, and peers that keep us choked or have
+    /// nothing we need make room for others after the idle timeout.
+    pub timeouts: PeerTimeouts,
+    /// How long the download may go without a connected peer or a newly verified piece before
+    /// it is given up on.
+    pub stall_timeout: Duration,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        Self {
+            max_peers: 30,
+            timeouts: PeerTimeouts::default(),
+            stall_timeout: Duration::from_secs(300),
+        }
+    }
+}
+
+/// Bookkeeping shared by all workers.
+struct State {
+    picker: PiecePicker,
+    /// Blocks received so far of each piece in progress.
+    buffers: HashMap<usize, Vec<u8>>,
+    /// Requests each connected peer should withdraw because another peer was faster.
+    cancels: HashMap<PeerKey, mpsc::UnboundedSender<Block>>,
+    /// When a piece was last verified or a peer last connected or disconnected.
+    last_progress: Instant,
+}
+
+/// State shared by the workers of a download, one per connected peer.
+struct Swarm {
+    info: Info,
+    info_hash: [u8; 20],
+    peer_id: PeerId,
+    config: DownloadConfig,
+    state: Mutex<State>,
+    /// Wakes idle workers when blocks are released or pieces are verified.
+    changed: Notify,
+    /// Complete pieces, verified and written by the coordinator.
+    completed: mpsc::UnboundedSender<(usize, Vec<u8>)>,
+    next_key: AtomicUsize,
+}
+
+/// Downloads from every peer that turns up, up to `max_peers` at a time, until all pieces are
+/// verified or no peers are left.
+///
+/// While `peers` is open more peers may still turn up, but once no peer has been connected
+/// and no piece verified for `stall_timeout` the download fails.
+///
+/// The files are only allocated once the first piece is verified, so a download that never
+/// gets anywhere leaves nothing behind.
+pub(crate) async fn fetch_pieces(
+    info: Info,
+    info_hash: [u8; 20],
+    peer_id: PeerId,
+    storage: Storage,
+    peers: &mut mpsc::UnboundedReceiver<PeerInfo>,
+    stats: Arc<TransferStats>,
+    config: DownloadConfig,
+) -> Result<(), BittorrentError> {
+    let (completed, mut completed_rx) = mpsc::unbounded_channel();
+    let swarm = Arc::new(Swarm {
+        state: Mutex::new(State {
+            picker: PiecePicker::new(&info),
+            buffers: HashMap::new(),
+            cancels: HashMap::new(),
+            last_progress: Instant::now(),
+        }),
+        info,
+        info_hash,
+        peer_id,
+        config,
+        changed: Notify::new(),
+        completed,
+        next_key: AtomicUsize::new(0),
+    });
+    let mut workers = JoinSet::new();
+    let mut peers_open = true;
+    let mut allocated = false;
+
+    loop {
+        // Pieces finished by workers that have exited since are still to be written.
+        while let Ok((index, piece)) = completed_rx.try_recv() {
+            finish_piece(&swarm, &storage, &mut allocated, &stats, index, piece)?;
+        }
+        let stalled_at = {
+            let state = swarm.state.lock().unwrap();
+            if state.picker.is_done() || !peers_open && workers.is_empty() {
+                break;
+            }
+            state.last_progress + swarm.config.stall_timeout
+        };
+        tokio::select! {
+            peer = peers.recv(), if peers_open && workers.len() < swarm.config.max_peers => {
+                match peer {
+                    Some(peer) => {
+                        workers.spawn(work(peer.addr, swarm.clone()));
+                    }
+                    None => peers_open = false,
+                }
+            }
+            Some((index, piece)) = completed_rx.recv() => {
+                // Only local errors such as a full disk are fatal, peers failing is expected.
+                finish_piece(&swarm, &storage, &mut allocated, &stats, index, piece)?;
+            }
+            Some(result) = workers.join_next() => result.expect("download worker panicked"),
+            // Peers that are still connecting give up on their own soon enough.
+            _ = sleep_until(stalled_at), if workers.is_empty() => {
+                return Err(DownloadError::Stalled(swarm.config.stall_timeout).into());
+            }
+        }
+    }
+
+    let picker = &swarm.state.lock().unwrap().picker;
+    if picker.is_done() {
+        // A torrent without pieces still has its empty files.
+        if !allocated {
+            storage.allocate()?;
+        }
+        Ok(())
+    } else {
+        Err(DownloadError::Incomplete {
+            missing: picker.num_missing(),
+            total: picker.num_pieces(),
+        }
+        .into())
+    }
+}
+
+/// Verifies a complete piece and writes it, or has it downloaded again from other peers.
+fn finish_piece(
+    swarm: &Swarm,
+    storage: &Storage,
+    allocated: &mut bool,
+    stats: &TransferStats,
+    index: usize,
+    piece: Vec<u8>,
+) -> Result<(), BittorrentError> {
+    let hash: [u8; 20] = Sha1::digest(&piece).into();
+    let valid = hash == swarm.info.pieces.0[index];
+    if valid {
+        if !*allocated {
+            storage.allocate()?;
+            *allocated = true;
+        }
+        storage.write_piece(index, &piece)?;
+        stats.add_downloaded(piece.len() as u64);
+    }
+    {
+        let mut state = swarm.state.lock().unwrap();
+        if valid {
+            state.picker.verified(index);
+            state.last_progress = Instant::now();
+        } else {
+            state.picker.failed(index);
+        }
+    }
+    swarm.changed.notify_waiters();
+    Ok(())
+}
+
+/// Downloads from one peer until it fails, goes silent, or the download is done.
+async fn work(addr: SocketAddr, swarm: Arc<Swarm>) {
+    let have = swarm.state.lock().unwrap().picker.have().clone();
+    let Ok(mut connection) = PeerConnection::connect(
+        addr,
+        swarm.info_hash,
+        swarm.peer_id,
+        &have,
+        swarm.config.timeouts.clone(),
+    )
+    .await
+    else {
+        return;
+    };
+
+    let key = swarm.next_key.fetch_add(1, Ordering::Relaxed);
+    let (cancel, mut cancels) = mpsc::unbounded_channel();
+    {
+        let mut state = swarm.state.lock().unwrap();
+        state.picker.add_peer(connection.session().pieces());
+        state.cancels.insert(key, cancel);
+        state.last_progress = Instant::now();
+    }
+
+    // Whatever went wrong with the peer, its requests go to the others.
+    let _ = exchange(&mut connection, key, &swarm, &mut cancels).await;
+
+    {
+        let mut state = swarm.state.lock().unwrap();
+        state.picker.remove_peer(connection.session().pieces());
+        state.cancels.remove(&key);
+        state.last_progress = Instant::now();
+        for &block in connection.session().requests() {
+            state.picker.release(key, block);
+        }
+    }
+    swarm.changed.notify_waiters();
+}
+
+/// Keeps the request window to a peer filled with blocks from the picker, and hands in what
+/// arrives.
+async fn exchange(
+    connection: &mut PeerConnection,
+    key: PeerKey,
+    swarm: &Swarm,
+    cancels: &mut mpsc::UnboundedReceiver<Block>,
+) -> Result<(), BittorrentError> {
+    let mut last_block = Instant::now();
+    // A peer that stops delivering blocks, even if it keeps sending other messages, is
+    // snubbing us. Its requests go to other peers, and it is asked for one block at a time
+    // until it delivers again. If that block doesn't arrive in time either, it is given up on.
+    let mut last_progress = Instant::now();
+    let mut snubbed = false;
+    let mut last_useful = Instant::now();
+
+    loop {
+        // Registered before looking at the picker so no release in between goes unnoticed.
+        let changed = swarm.changed.notified();
+        tokio::pin!(changed);
+        changed.as_mut().enable();
+
+        let in_flight = connection.session().requests().len();
+        let limit = if snubbed { 1 } else { connection.window.size() };
+        let room = limit.saturating_sub(in_flight);
+        let (interesting, picked) = {
+            let mut state = swarm.state.lock().unwrap();
+            let has = connection.session().pieces();
+            if state.picker.is_done() {
+                return Ok(());
+            }
+            let interesting = state.picker.is_interesting(key, has);
+            let picked = if interesting && !connection.is_choked() && room > 0 {
+                state.picker.pick(key, has, room)
+            } else {
+                Vec::new()
+            };
+            (interesting, picked)
+        };
+        // Peers may announce pieces at any time, so one with nothing we need, or one that keeps
+        // us choked, is kept around for a while before its slot goes to another peer.
+        connection.set_interested(interesting).await?;
+        if interesting && !connection.is_choked() {
+            last_useful = Instant::now();
+        }
+        if in_flight == 0 && !picked.is_empty() {
+            last_block = Instant::now();
+            last_progress = last_block;
+        }
+        for block in picked {
+            connection.request(block).await?;
+        }
+
+        let snub_at = last_progress + connection.timeouts().request;
+        let waiting = !connection.session().requests().is_empty();
+        let idle_at = last_useful + connection.timeouts().idle;
+        let idle = !interesting || connection.is_choked();
+        let event = tokio::select! {
+            event = connection.next_event() => event?,
+            Some(block) = cancels.recv() => {
+                connection.cancel(block).await?;
+                continue;
+            }
+            _ = sleep_until(snub_at), if waiting => {
+                if snubbed {
+                    return Err(DownloadError::Timeout.into());
+                }
+                snub(connection, key, swarm).await?;
+                snubbed = true;
+                continue;
+            }
+            _ = sleep_until(idle_at), if idle => return Err(DownloadError::Timeout.into()),
+            _ = changed => continue,
+        };
+        match event {
+            PeerEvent::Choked { dropped } => {
+                let mut state = swarm.state.lock().unwrap();
+                for block in dropped {
+                    state.picker.release(key, block);
+                }
+                drop(state);
+                swarm.changed.notify_waiters();
+            }
+            PeerEvent::Have(indices) => {
+                let mut state = swarm.state.lock().unwrap();
+                for index in indices {
+                    state.picker.add_have(index);
+                }
+            }
+            PeerEvent::Block { block, data } => {
+                connection.window.record(block.len, last_block.elapsed());
+                last_block = Instant::now();
+                last_progress = last_block;
+                snubbed = false;
+                receive_block(swarm, key, block, &data);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Withdraws every request from a snubbing peer, so other peers can take them.
+async fn snub(
+    connection: &mut PeerConnection,
+    key: PeerKey,
+    swarm: &Swarm,
+) -> Result<(), BittorrentError> {
+    let blocks: Vec<Block> = connection.session().requests().iter().copied().collect();
+    for &block in &blocks {
+        connection.cancel(block).await?;
+    }
+    {
+        let mut state = swarm.state.lock().unwrap();
+        for block in blocks {
+            state.picker.release(key, block);
+        }
+    }
+    swarm.changed.notify_waiters();
+    Ok(())
+}
+
+/// Stores a block, withdraws duplicate requests for it in endgame mode, and hands the piece to
+/// the coordinator once it is complete.
+fn receive_block(swarm: &Swarm, key: PeerKey, block: Block, data: &[u8]) {
+    let mut state = swarm.state.lock().unwrap();
+    let Received::New {
+        cancel,
+        piece_complete,
+    } = state.picker.received(key, block)
+    else {
+        return;
+    };
+    for peer in cancel {
+        if let Some(cancels) = state.cancels.get(&peer) {
+            let _ = cancels.send(block);
+        }
+    }
+    let piece_len = swarm.info.piece_len(block.index);
+    let buffer = state
+        .buffers
+        .entry(block.index)
+        .or_insert_with(|| vec![0; piece_len]);
+    buffer[block.begin..block.begin + block.len].copy_from_slice(data);
+    if piece_complete {
+        let piece = state.buffers.remove(&block.index).expect("buffer exists");
+        let _ = swarm.completed.send((block.index, piece));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::fs;
+    use std::sync::atomic::Ordering;
+
+    use super::*;
+    use crate::download::testing::{data, multi_file_torrent, FakeSeeder};
+
+    async fn fetch(
+        info: &Info,
+        seeders: Vec<SocketAddr>,
+        config: DownloadConfig,
+    ) -> (tempfile::TempDir, Result<(), BittorrentError>) {
+        let (tx, mut peers) = mpsc::unbounded_channel();
+        for seeder in seeders {
+            tx.send(PeerInfo::from(seeder)).unwrap();
+        }
+        drop(tx);
+        fetch_from(info, &mut peers, config).await
+    }
+
+    async fn fetch_from(
+        info: &Info,
+        peers: &mut mpsc::UnboundedReceiver<PeerInfo>,
+        config: DownloadConfig,
+    ) -> (tempfile::TempDir, Result<(), BittorrentError>) {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(info.clone(), dir.path()).unwrap();
+        let result = fetch_pieces(
+            info.clone(),
+            [7; 20],
+            PeerId::generate(),
+            storage,
+            peers,
+            Arc::new(TransferStats::new(info.total_length() as u64)),
+            config,
+        )
+        .await;
+        (dir, result)
+    }
+
+    fn read_multi_file(dir: &tempfile::TempDir) -> Vec<u8> {
+        let root = dir.path().join("dir");
+        let mut written = fs::read(root.join("a.bin")).unwrap();
+        written.extend(fs::read(root.join("sub/b.bin")).unwrap());
+        written
+    }
+
+    #[tokio::test]
+    async fn download_from_several_peers() {
+        let data = data(100_000);
+        let info = multi_file_torrent(&data, 32_768);
+        let good = FakeSeeder::new(&info, [7; 20], data.clone());
+        // The first peer corrupts a piece and lacks another, the second fills the gaps.
+        let bad = FakeSeeder {
+            has: [0, 1, 2].into(),
+            corrupt: [1].into(),
+            ..good.clone()
+        }
+        .spawn()
+        .await;
+        let good = good.spawn().await;
+
+        let (dir, result) = fetch(&info, vec![bad, good], DownloadConfig::default()).await;
+        result.unwrap();
+        assert_eq!(data, read_multi_file(&dir));
+    }
+
+    #[tokio::test]
+    async fn download_from_peers_announcing_late() {
+        let data = data(100_000);
+        let info = multi_file_torrent(&data, 32_768);
+        // Unchokes before telling which pieces it has, one at a time.
+        let seeder = FakeSeeder {
+            announce_with_have: true,
+            ..FakeSeeder::new(&info, [7; 20], data.clone())
+        }
+        .spawn()
+        .await;
+
+        let (dir, result) = fetch(&info, vec![seeder], DownloadConfig::default()).await;
+        result.unwrap();
+        assert_eq!(data, read_multi_file(&dir));
+    }
+
+    #[tokio::test]
+    async fn spread_pieces_across_peers() {
+        let data = data(1 << 20);
+        let info = multi_file_torrent(&data, 32_768);
+        let seeder = FakeSeeder {
+            latency: Duration::from_millis(5),
+            ..FakeSeeder::new(&info, [7; 20], data.clone())
+        };
+        let a = FakeSeeder {
+            served: Default::default(),
+            ..seeder.clone()
+        };
+        let b = FakeSeeder {
+            served: Default::default(),
+            ..seeder.clone()
+        };
+        let (a_served, b_served) = (a.served.clone(), b.served.clone());
+        let seeders = vec![a.spawn().await, b.spawn().await];
+
+        let (dir, result) = fetch(&info, seeders, DownloadConfig::default()).await;
+        result.unwrap();
+        assert_eq!(data, read_multi_file(&dir));
+        assert!(a_served.load(Ordering::Relaxed) > 0);
+        assert!(b_served.load(Ordering::Relaxed) > 0);
+    }
+
+    #[tokio::test]
+    async fn reassign_pieces_of_failing_peers() {
+        let data = data(100_000);
+        let info = multi_file_torrent(&data, 32_768);
+        let good = FakeSeeder::new(&info, [7; 20], data.clone());
+        let stalling = FakeSeeder {
+            stall: [0, 1, 2, 3].into(),
+            ..good.clone()
+        }
+        .spawn()
+        .await;
+        let dropping = FakeSeeder {
+            disconnect_after: Some(1),
+            ..good.clone()
+        }
+        .spawn()
+        .await;
+        let lacking = FakeSeeder {
+            has: HashSet::new(),
+            ..good.clone()
+        }
+        .spawn()
+        .await;
+        // Its handshake is delayed, so the failing peers take their pieces first.
+        let good = FakeSeeder {
+            latency: Duration::from_millis(50),
+            ..good
+        }
+        .spawn()
+        .await;
+
+        let config = DownloadConfig {
+            timeouts: PeerTimeouts {
+                request: Duration::from_millis(300),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (dir, result) = fetch(&info, vec![stalling, dropping, lacking, good], config).await;
+        result.unwrap();
+        assert_eq!(data, read_multi_file(&dir));
+    }
+
+    #[tokio::test]
+    async fn withdraw_requests_from_snubbing_peers() {
+        let data = data(100_000);
+        let info = multi_file_torrent(&data, 32_768);
+        // The only peer, so endgame mode can't ask anyone else for the stalled piece.
+        let seeder = FakeSeeder {
+            stall: [0].into(),
+            ..FakeSeeder::new(&info, [7; 20], data.clone())
+        };
+        let cancelled = seeder.cancelled.clone();
+        let seeder = seeder.spawn().await;
+
+        // The peer never goes silent, so only giving up on it once it stays snubbed lets the
+        // download end.
+        let config = DownloadConfig {
+            timeouts: PeerTimeouts {
+                request: Duration::from_millis(100),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (_dir, result) =
+            tokio::time::timeout(Duration::from_secs(5), fetch(&info, vec![seeder], config))
+                .await
+                .expect("a peer that stays snubbed should be disconnected");
+        assert!(matches!(
+            result,
+            Err(BittorrentError::DownloadError(DownloadError::Incomplete {
+                missing: 1,
+                total: 4
+            }))
+        ));
+        // Both blocks of the stalled piece were withdrawn before the peer was given up on.
+        assert_eq!(2, cancelled.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn finish_in_endgame_mode() {
+        let data = data(100_000);
+        let info = multi_file_torrent(&data, 32_768);
+        // Holds on to the first blocks it is asked for without ever sending them.
+        let stalling = FakeSeeder {
+            stall: (0..4).collect(),
+            ..FakeSeeder::new(&info, [7; 20], data.clone())
+        };
+        let cancelled = stalling.cancelled.clone();
+        let stalling = stalling.spawn().await;
+        let good = FakeSeeder {
+            latency: Duration::from_millis(50),
+            ..FakeSeeder::new(&info, [7; 20], data.clone())
+        }
+        .spawn()
+        .await;
+
+        // Well within the timeout, the good peer is asked for the stalled blocks as well.
+        let config = DownloadConfig {
+            timeouts: PeerTimeouts {
+                request: Duration::from_secs(10),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (dir, result) = tokio::time::timeout(
+            Duration::from_secs(5),
+            fetch(&info, vec![stalling, good], config),
+        )
+        .await
+        .expect("endgame mode should not wait for the stalling peer");
+        result.unwrap();
+        assert_eq!(data, read_multi_file(&dir));
+        // Cancels are sent just before the download ends, give them a moment to arrive.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(cancelled.load(Ordering::Relaxed) > 0);
+    }
+
+    #[tokio::test]
+    async fn make_room_for_useful_peers() {
+        let data = data(100_000);
+        let info = multi_file_torrent(&data, 32_768);
+        let good = FakeSeeder::new(&info, [7; 20], data.clone());
+        let choking = FakeSeeder {
+            never_unchoke: true,
+            ..good.clone()
+        }
+        .spawn()
+        .await;
+        let lacking = FakeSeeder {
+            has: HashSet::new(),
+            ..good.clone()
+        }
+        .spawn()
+        .await;
+        let good = good.spawn().await;
+
+        // Only one slot, which the good peer only gets once the others are given up on.
+        let config = DownloadConfig {
+            max_peers: 1,
+            timeouts: PeerTimeouts {
+                idle: Duration::from_millis(200),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (dir, result) = tokio::time::timeout(
+            Duration::from_secs(5),
+            fetch(&info, vec![choking, lacking, good], config),
+        )
+        .await
+        .expect("peers that can't help should not hold on to their slots");
+        result.unwrap();
+        assert_eq!(data, read_multi_file(&dir));
+    }
+
+    #[tokio::test]
+    async fn report_missing_pieces() {
+        let data = data(100_000);
+        let info = multi_file_torrent(&data, 32_768);
+        let seeder = FakeSeeder {
+            has: [0, 3].into(),
+            ..FakeSeeder::new(&info, [7; 20], data.clone())
+        };
+        let wrong_torrent = FakeSeeder {
+            info_hash: [8; 20],
+            ..seeder.clone()
+        }
+        .spawn()
+        .await;
+        let seeder = seeder.spawn().await;
+
+        // The seeder stays connected for a while in case it announces more pieces.
+        let config = DownloadConfig {
+            timeouts: PeerTimeouts {
+                idle: Duration::from_millis(300),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (_dir, result) = fetch(&info, vec![wrong_torrent, seeder], config).await;
+        assert!(matches!(
+            result,
+            Err(BittorrentError::DownloadError(DownloadError::Incomplete {
+                missing: 2,
+                total: 4
+            }))
+        ));
+    }
+
+    #[tokio::test]
+    async fn give_up_when_no_peer_turns_up() {
+        let data = data(100_000);
+        let info = multi_file_torrent(&data, 32_768);
+        // The trackers might still hand out peers, but none come.
+        let (_tx, mut peers) = mpsc::unbounded_channel();
+        let config = DownloadConfig {
+            stall_timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let (dir, result) = tokio::time::timeout(
+            Duration::from_secs(5),
+            fetch_from(&info, &mut peers, config),
+        )
+        .await
+        .expect("a download without peers should not wait forever");
+        assert!(matches!(
+            result,
+            Err(BittorrentError::DownloadError(DownloadError::Stalled(_)))
+        ));
+        assert_eq!(0, fs::read_dir(dir.path()).unwrap().count());
+    }
+}
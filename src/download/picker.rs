@@ -0,0 +1,476 @@
+//! Decides which blocks to request from which peer.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use super::{blocks, BLOCK_MAX};
+use crate::peer::Bitfield;
+use crate::random::Rng;
+use crate::torrent::Info;
+
+/// Pieces picked at random before switching to rarest-first, so we quickly have something
+/// to share instead of competing with everyone for the same rare piece.
+const RANDOM_FIRST: usize = 4;
+
+/// Identifies a connected peer within one download.
+pub type PeerKey = usize;
+
+/// A block of a piece, the unit of requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Block {
+    pub index: usize,
+    pub begin: usize,
+    pub len: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum BlockState {
+    Open,
+    /// Requested from these peers, more than one only in endgame mode.
+    Requested(Vec<PeerKey>),
+    Received,
+}
+
+/// A piece some of whose blocks have been requested.
+#[derive(Debug)]
+struct Partial {
+    blocks: Vec<BlockState>,
+    received: usize,
+    /// Peers that delivered blocks, blamed if the piece fails verification.
+    contributors: HashSet<PeerKey>,
+}
+
+/// What became of a received block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Received {
+    /// The block had already arrived from another peer.
+    Duplicate,
+    /// The block is new, requests for it to `cancel` are no longer needed.
+    New {
+        cancel: Vec<PeerKey>,
+        /// Every block of the piece has arrived and it can be verified.
+        piece_complete: bool,
+    },
+}
+
+/// Chooses pieces rarest-first, finishing started pieces before opening new ones, and enters
+/// endgame mode once every missing block has been requested, asking several peers for the
+/// same blocks.
+#[derive(Debug)]
+pub struct PiecePicker {
+    piece_length: usize,
+    total_length: usize,
+    /// Number of connected peers that have each piece.
+    availability: Vec<u32>,
+    verified: Bitfield,
+    partial: BTreeMap<usize, Partial>,
+    /// Peers that sent a piece which failed verification, and are not asked for it again.
+    excluded: HashMap<usize, HashSet<PeerKey>>,
+    rng: Rng,
+}
+
+impl PiecePicker {
+    pub fn new(info: &Info) -> Self {
+        let num_pieces = info.pieces.0.len();
+        Self {
+            piece_length: info.piece_length,
+            total_length: info.total_length(),
+            availability: vec![0; num_pieces],
+            verified: Bitfield::new(num_pieces),
+            partial: BTreeMap::new(),
+            excluded: HashMap::new(),
+            rng: Rng::new(),
+        }
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.verified.num_pieces()
+    }
+
+    pub fn num_missing(&self) -> usize {
+        self.num_pieces() - self.verified.count()
+    }
+
+    /// Pieces downloaded and verified so far.
+    pub fn have(&self) -> &Bitfield {
+        &self.verified
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.num_missing() == 0
+    }
+
+    pub fn availability(&self, index: usize) -> u32 {
+        self.availability[index]
+    }
+
+    /// Counts the pieces of a newly connected peer.
+    pub fn add_peer(&mut self, has: &Bitfield) {
+        for index in has.iter() {
+            self.availability[index] += 1;
+        }
+    }
+
+    /// Forgets the pieces of a peer that disconnected.
+    pub fn remove_peer(&mut self, has: &Bitfield) {
+        for index in has.iter() {
+            self.availability[index] = self.availability[index].saturating_sub(1);
+        }
+    }
+
+    /// Counts a piece a peer announced with `have`.
+    pub fn add_have(&mut self, index: usize) {
+        if let Some(count) = self.availability.get_mut(index) {
+            *count += 1;
+        }
+    }
+
+    /// Whether `peer` has any piece we still need, regardless of who it is requested from.
+    pub fn is_interesting(&self, peer: PeerKey, has: &Bitfield) -> bool {
+        has.difference(&self.verified)
+            .iter()
+            .any(|index| !self.is_excluded(peer, index))
+    }
+
+    /// True once no missing block is left unrequested.
+    pub fn is_endgame(&self) -> bool {
+        let open = self
+            .partial
+            .values()
+            .any(|partial| partial.blocks.contains(&BlockState::Open));
+        !open && self.partial.len() == self.num_missing()
+    }
+
+    /// Picks up to `max` blocks to request from `peer`.
+    pub fn pick(&mut self, peer: PeerKey, has: &Bitfield, max: usize) -> Vec<Block> {
+        let mut picked = Vec::new();
+
+        // Finishing pieces makes them verifiable and shareable sooner.
+        let started: Vec<usize> = self.partial.keys().copied().collect();
+        for index in started {
+            if picked.len() == max {
+                return picked;
+            }
+            if self.is_wanted(peer, index, has) {
+                self.pick_open(peer, index, max, &mut picked);
+            }
+        }
+
+        while picked.len() < max {
+            let Some(index) = self.pick_new_piece(peer, has) else {
+                break;
+            };
+            let num_blocks = self.piece_len(index).div_ceil(BLOCK_MAX);
+            self.partial.insert(
+                index,
+                Partial {
+                    blocks: vec![BlockState::Open; num_blocks],
+                    received: 0,
+                    contributors: HashSet::new(),
+                },
+            );
+            self.pick_open(peer, index, max, &mut picked);
+        }
+
+        if picked.is_empty() && self.is_endgame() {
+            self.pick_endgame(peer, has, max, &mut picked);
+        }
+        picked
+    }
+
+    /// Records a block from `peer`.
+    pub fn received(&mut self, peer: PeerKey, block: Block) -> Received {
+        let Some(partial) = self.partial.get_mut(&block.index) else {
+            return Received::Duplicate;
+        };
+        let state = &mut partial.blocks[block.begin / BLOCK_MAX];
+        let cancel = match std::mem::replace(state, BlockState::Received) {
+            BlockState::Received => return Received::Duplicate,
+            BlockState::Open => Vec::new(),
+            BlockState::Requested(peers) => peers.into_iter().filter(|p| *p != peer).collect(),
+        };
+        partial.received += 1;
+        partial.contributors.insert(peer);
+        Received::New {
+            cancel,
+            piece_complete: partial.received == partial.blocks.len(),
+        }
+    }
+
+    /// Gives up on a request to `peer`, making the block available again if nobody else has
+    /// it requested.
+    pub fn release(&mut self, peer: PeerKey, block: Block) {
+        let Some(partial) = self.partial.get_mut(&block.index) else {
+            return;
+        };
+        let state = &mut partial.blocks[block.begin / BLOCK_MAX];
+        if let BlockState::Requested(peers) = state {
+            peers.retain(|p| *p != peer);
+            if peers.is_empty() {
+                *state = BlockState::Open;
+            }
+        }
+    }
+
+    /// Marks a complete piece as verified.
+    pub fn verified(&mut self, index: usize) {
+        self.partial.remove(&index);
+        self.excluded.remove(&index);
+        self.verified.set(index);
+    }
+
+    /// Starts a piece over after it failed verification, without the peers that sent it.
+    pub fn failed(&mut self, index: usize) {
+        if let Some(partial) = self.partial.remove(&index) {
+            self.excluded
+                .entry(index)
+                .or_default()
+                .extend(partial.contributors);
+        }
+    }
+
+    fn piece_len(&self, index: usize) -> usize {
+        let start = index * self.piece_length;
+        self.piece_length
+            .min(self.total_length.saturating_sub(start))
+    }
+
+    fn is_wanted(&self, peer: PeerKey, index: usize, has: &Bitfield) -> bool {
+        !self.verified.has(index) && has.has(index) && !self.is_excluded(peer, index)
+    }
+
+    fn is_excluded(&self, peer: PeerKey, index: usize) -> bool {
+        self.excluded
+            .get(&index)
+            .is_some_and(|peers| peers.contains(&peer))
+    }
+
+    /// Random among the first few pieces, rarest after that, with ties broken randomly.
+    fn pick_new_piece(&mut self, peer: PeerKey, has: &Bitfield) -> Option<usize> {
+        let candidates: Vec<usize> = has
+            .difference(&self.verified)
+            .iter()
+            .filter(|index| !self.partial.contains_key(index) && !self.is_excluded(peer, *index))
+            .collect();
+        let rarest = if self.verified.count() < RANDOM_FIRST {
+            candidates
+        } else {
+            let min = candidates.iter().map(|&i| self.availability[i]).min()?;
+            candidates
+                .into_iter()
+                .filter(|&i| self.availability[i] == min)
+                .collect()
+        };
+        if rarest.is_empty() {
+            return None;
+        }
+        Some(rarest[self.rng.below(rarest.len())])
+    }
+
+    fn pick_open(&mut self, peer: PeerKey, index: usize, max: usize, picked: &mut Vec<Block>) {
+        let piece_len = self.piece_len(index);
+        let partial = self.partial.get_mut(&index).expect("piece is started");
+        for (state, range) in partial.blocks.iter_mut().zip(blocks(piece_len)) {
+            if picked.len() == max {
+                return;
+            }
+            if *state == BlockState::Open {
+                *state = BlockState::Requested(vec![peer]);
+                picked.push(Block {
+                    index,
+                    begin: range.start,
+                    len: range.len(),
+                });
+            }
+        }
+    }
+
+    /// Requests blocks other peers are already working on, least requested first.
+    fn pick_endgame(&mut self, peer: PeerKey, has: &Bitfield, max: usize, picked: &mut Vec<Block>) {
+        let mut candidates = Vec::new();
+        for (&index, partial) in &self.partial {
+            if !self.is_wanted(peer, index, has) {
+                continue;
+            }
+            let piece_len = self.piece_len(index);
+            for (block, (state, range)) in partial.blocks.iter().zip(blocks(piece_len)).enumerate()
+            {
+                if let BlockState::Requested(peers) = state {
+                    if !peers.contains(&peer) {
+                        candidates.push((peers.len(), index, block, range));
+                    }
+                }
+            }
+        }
+        candidates.sort_by_key(|(requests, index, block, _)| (*requests, *index, *block));
+        for (_, index, block, range) in candidates.into_iter().take(max) {
+            let partial = self.partial.get_mut(&index).expect("piece is started");
+            if let BlockState::Requested(peers) = &mut partial.blocks[block] {
+                peers.push(peer);
+            }
+            picked.push(Block {
+                index,
+                begin: range.start,
+                len: range.len(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::testing::{data, single_file_torrent};
+
+    /// Ten pieces of two blocks each, the last one a single short block.
+    fn picker() -> PiecePicker {
+        let data = data(9 * 2 * BLOCK_MAX + 100);
+        PiecePicker::new(&single_file_torrent(&data, 2 * BLOCK_MAX))
+    }
+
+    fn pieces(has: impl Fn(usize) -> bool) -> Bitfield {
+        let mut pieces = Bitfield::new(10);
+        for index in (0..10).filter(|&index| has(index)) {
+            pieces.set(index);
+        }
+        pieces
+    }
+
+    fn all() -> Bitfield {
+        pieces(|_| true)
+    }
+
+    fn complete(picker: &mut PiecePicker, peer: PeerKey, index: usize) {
+        for block in picker.pick(peer, &pieces(|i| i == index), usize::MAX) {
+            picker.received(peer, block);
+        }
+        picker.verified(index);
+    }
+
+    #[test]
+    fn pick_rarest_after_random_first() {
+        let mut picker = picker();
+        picker.add_peer(&all());
+        picker.add_peer(&pieces(|i| i != 7));
+        picker.add_peer(&pieces(|i| i != 7 && i != 8));
+        for index in 0..RANDOM_FIRST {
+            complete(&mut picker, 0, index);
+        }
+        assert_eq!(
+            vec![7, 7],
+            picker
+                .pick(0, &all(), 2)
+                .iter()
+                .map(|b| b.index)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![8, 8],
+            picker
+                .pick(0, &all(), 2)
+                .iter()
+                .map(|b| b.index)
+                .collect::<Vec<_>>()
+        );
+        // The remaining pieces are equally common.
+        let next = picker.pick(1, &all(), 1)[0].index;
+        assert!([4, 5, 6, 9].contains(&next), "{next}");
+    }
+
+    #[test]
+    fn pick_randomly_at_first() {
+        let firsts: HashSet<usize> = (0..20)
+            .map(|_| {
+                let mut picker = picker();
+                picker.add_peer(&all());
+                picker.pick(0, &all(), 1)[0].index
+            })
+            .collect();
+        assert!(firsts.len() > 1);
+    }
+
+    #[test]
+    fn finish_started_pieces_first() {
+        let mut picker = picker();
+        // The last piece has a single block.
+        let all = pieces(|i| i < 9);
+        picker.add_peer(&all);
+        let first = picker.pick(0, &all, 1);
+        let second = picker.pick(1, &all, 1);
+        assert_eq!(first[0].index, second[0].index);
+        assert_eq!(BLOCK_MAX, second[0].begin);
+
+        // Released blocks are handed out again before new pieces.
+        picker.release(0, first[0]);
+        assert_eq!(first, picker.pick(2, &all, 1));
+    }
+
+    #[test]
+    fn split_short_last_piece() {
+        let mut picker = picker();
+        assert_eq!(
+            vec![Block {
+                index: 9,
+                begin: 0,
+                len: 100
+            }],
+            picker.pick(0, &pieces(|i| i == 9), 10)
+        );
+    }
+
+    #[test]
+    fn request_remaining_blocks_in_endgame() {
+        let mut picker = picker();
+        for index in 0..9 {
+            complete(&mut picker, 0, index);
+        }
+        assert!(!picker.is_endgame());
+        let slow = picker.pick(1, &all(), 10);
+        assert_eq!(1, slow.len());
+        assert!(picker.is_endgame());
+
+        // Another peer gets the same block, but never twice.
+        assert_eq!(slow, picker.pick(2, &all(), 10));
+        assert!(picker.pick(2, &all(), 10).is_empty());
+
+        assert_eq!(
+            Received::New {
+                cancel: vec![1],
+                piece_complete: true
+            },
+            picker.received(2, slow[0])
+        );
+        assert_eq!(Received::Duplicate, picker.received(1, slow[0]));
+        picker.verified(9);
+        assert!(picker.is_done());
+    }
+
+    #[test]
+    fn exclude_peers_that_sent_corrupt_pieces() {
+        let mut picker = picker();
+        let only = pieces(|i| i == 3);
+        complete_unverified(&mut picker, 0, 3);
+        picker.failed(3);
+        assert!(picker.pick(0, &only, 10).is_empty());
+        assert!(!picker.is_interesting(0, &only));
+        assert_eq!(2, picker.pick(1, &only, 10).len());
+    }
+
+    fn complete_unverified(picker: &mut PiecePicker, peer: PeerKey, index: usize) {
+        for block in picker.pick(peer, &pieces(|i| i == index), usize::MAX) {
+            picker.received(peer, block);
+        }
+    }
+
+    #[test]
+    fn track_availability() {
+        let mut picker = picker();
+        picker.add_peer(&pieces(|i| i < 5));
+        picker.add_have(7);
+        picker.add_have(70);
+        assert_eq!(1, picker.availability(0));
+        assert_eq!(1, picker.availability(7));
+        assert_eq!(0, picker.availability(5));
+        picker.remove_peer(&pieces(|i| i < 5));
+        assert_eq!(0, picker.availability(0));
+    }
+}
@@ -0,0 +1,359 @@
+//! Protocol state of a connection to a peer, kept apart from how messages are sent.
+
+use std::collections::HashSet;
+
+use bytes::Bytes;
+
+use super::{Block, DownloadError};
+use crate::peer::{Bitfield, MessageTag, PeerMessage};
+
+/// Something the peer did that the download may need to react to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerEvent {
+    /// The peer choked us, discarding these outstanding requests.
+    Choked {
+        dropped: Vec<Block>,
+    },
+    Unchoked,
+    Interested,
+    NotInterested,
+    /// Pieces the peer announced in a `have` or `bitfield` message that it didn't have before.
+    Have(Vec<usize>),
+    /// One of our outstanding requests was fulfilled.
+    Block {
+        block: Block,
+        data: Bytes,
+    },
+    /// The peer asks for a block, only reported while we aren't choking it.
+    Request(Block),
+    /// The peer withdraws a request.
+    Cancel(Block),
+}
+
+/// Choking and interest on both sides of a connection, the pieces the peer has, and the
+/// requests we are waiting on.
+///
+/// Messages are accepted in any order: peers need not send a bitfield, may announce pieces
+/// before or after unchoking us, and may choke us at any time, which drops every request.
+#[derive(Debug, Clone)]
+pub struct PeerSession {
+    am_choking: bool,
+    am_interested: bool,
+    peer_choking: bool,
+    peer_interested: bool,
+    pieces: Bitfield,
+    requests: HashSet<Block>,
+}
+
+impl PeerSession {
+    /// The state of a fresh connection, both sides choking and not interested.
+    pub fn new(num_pieces: usize) -> Self {
+        Self {
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+            pieces: Bitfield::new(num_pieces),
+            requests: HashSet::new(),
+        }
+    }
+
+    pub fn am_choking(&self) -> bool {
+        self.am_choking
+    }
+
+    pub fn am_interested(&self) -> bool {
+        self.am_interested
+    }
+
+    pub fn peer_choking(&self) -> bool {
+        self.peer_choking
+    }
+
+    pub fn peer_interested(&self) -> bool {
+        self.peer_interested
+    }
+
+    /// Pieces the peer announced.
+    pub fn pieces(&self) -> &Bitfield {
+        &self.pieces
+    }
+
+    pub fn has_piece(&self, index: usize) -> bool {
+        self.pieces.has(index)
+    }
+
+    /// Our requests the peer hasn't answered yet.
+    pub fn requests(&self) -> &HashSet<Block> {
+        &self.requests
+    }
+
+    /// Changes whether we choke the peer, returning the message to tell it if that's news.
+    pub fn set_choking(&mut self, choking: bool) -> Option<PeerMessage> {
+        if self.am_choking == choking {
+            return None;
+        }
+        self.am_choking = choking;
+        Some(if choking {
+            PeerMessage::Choke
+        } else {
+            PeerMessage::Unchoke
+        })
+    }
+
+    /// Changes whether we want pieces from the peer, returning the message to tell it if
+    /// that's news.
+    pub fn set_interested(&mut self, interested: bool) -> Option<PeerMessage> {
+        if self.am_interested == interested {
+            return None;
+        }
+        self.am_interested = interested;
+        Some(if interested {
+            PeerMessage::Interested
+        } else {
+            PeerMessage::NotInterested
+        })
+    }
+
+    /// Records a request for `block`, returning the message to send.
+    pub fn request(&mut self, block: Block) -> PeerMessage {
+        self.requests.insert(block);
+        PeerMessage::Request {
+            index: block.index as u32,
+            begin: block.begin as u32,
+            length: block.len as u32,
+        }
+    }
+
+    /// Withdraws the request for `block`, returning the message to send if it is outstanding.
+    pub fn cancel(&mut self, block: Block) -> Option<PeerMessage> {
+        self.requests.remove(&block).then_some(PeerMessage::Cancel {
+            index: block.index as u32,
+            begin: block.begin as u32,
+            length: block.len as u32,
+        })
+    }
+
+    /// Updates the state for a message from the peer, and reports what changed.
+    pub fn handle(&mut self, message: PeerMessage) -> Result<Option<PeerEvent>, DownloadError> {
+        let event = match message {
+            PeerMessage::KeepAlive | PeerMessage::Port(_) => None,
+            PeerMessage::Choke => {
+                self.peer_choking = true;
+                let mut dropped: Vec<_> = self.requests.drain().collect();
+                dropped.sort_unstable_by_key(|block| (block.index, block.begin));
+                Some(PeerEvent::Choked { dropped })
+            }
+            PeerMessage::Unchoke => {
+                self.peer_choking = false;
+                Some(PeerEvent::Unchoked)
+            }
+            PeerMessage::Interested => {
+                self.peer_interested = true;
+                Some(PeerEvent::Interested)
+            }
+            PeerMessage::NotInterested => {
+                self.peer_interested = false;
+                Some(PeerEvent::NotInterested)
+            }
+            PeerMessage::Have(index) => {
+                let index = index as usize;
+                if index >= self.pieces.num_pieces() {
+                    return Err(DownloadError::MalformedMessage(MessageTag::Have));
+                }
+                if self.pieces.has(index) {
+                    return Ok(None);
+                }
+                self.pieces.set(index);
+                Some(PeerEvent::Have(vec![index]))
+            }
+            PeerMessage::Bitfield(bitfield) => {
+                let theirs = Bitfield::from_bytes(bitfield.as_bytes(), self.pieces.num_pieces())?;
+                // Peers don't lose pieces, so pieces already announced stay.
+                let new = theirs.difference(&self.pieces);
+                self.pieces.union_with(&theirs);
+                Some(PeerEvent::Have(new.iter().collect()))
+            }
+            PeerMessage::Piece {
+                index,
+                begin,
+                block: data,
+            } => {
+                let (index, begin) = (index as usize, begin as usize);
+                // Cancelled requests may still be answered.
+                let Some(&block) = self
+                    .requests
+                    .iter()
+                    .find(|block| block.index == index && block.begin == begin)
+                else {
+                    return Ok(None);
+                };
+                if data.len() != block.len {
+                    return Err(DownloadError::MalformedMessage(MessageTag::Piece));
+                }
+                self.requests.remove(&block);
+                Some(PeerEvent::Block { block, data })
+            }
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            } => self
+                .peer_request(index, begin, length)
+                .map(PeerEvent::Request),
+            PeerMessage::Cancel {
+                index,
+                begin,
+                length,
+            } => self
+                .peer_request(index, begin, length)
+                .map(PeerEvent::Cancel),
+        };
+        Ok(event)
+    }
+
+    /// A block the peer asks for or withdraws, ignored while we choke it.
+    fn peer_request(&self, index: u32, begin: u32, length: u32) -> Option<Block> {
+        (!self.am_choking).then_some(Block {
+            index: index as usize,
+            begin: begin as usize,
+            len: length as usize,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::BitfieldError;
+
+    fn block(index: usize, begin: usize) -> Block {
+        Block {
+            index,
+            begin,
+            len: 100,
+        }
+    }
+
+    fn piece(block: Block) -> PeerMessage {
+        PeerMessage::Piece {
+            index: block.index as u32,
+            begin: block.begin as u32,
+            block: Bytes::from(vec![0; block.len]),
+        }
+    }
+
+    #[test]
+    fn accept_messages_in_any_order() {
+        let mut session = PeerSession::new(10);
+        // No bitfield, a keep-alive, and pieces announced only after unchoking.
+        assert_eq!(None, session.handle(PeerMessage::KeepAlive).unwrap());
+        assert_eq!(
+            Some(PeerEvent::Unchoked),
+            session.handle(PeerMessage::Unchoke).unwrap()
+        );
+        assert!(!session.peer_choking());
+        assert_eq!(
+            Some(PeerEvent::Have(vec![3])),
+            session.handle(PeerMessage::Have(3)).unwrap()
+        );
+        assert_eq!(None, session.handle(PeerMessage::Have(3)).unwrap());
+
+        // A late bitfield only reports what's new.
+        let mut bitfield = Bitfield::new(10);
+        bitfield.set(3);
+        bitfield.set(9);
+        assert_eq!(
+            Some(PeerEvent::Have(vec![9])),
+            session.handle(PeerMessage::Bitfield(bitfield)).unwrap()
+        );
+        assert!(session.has_piece(3) && session.has_piece(9) && !session.has_piece(4));
+
+        assert!(matches!(
+            session.handle(PeerMessage::Have(10)),
+            Err(DownloadError::MalformedMessage(MessageTag::Have))
+        ));
+        assert!(matches!(
+            session.handle(PeerMessage::Bitfield(Bitfield::new(20))),
+            Err(DownloadError::InvalidBitfield(BitfieldError::WrongLength {
+                expected: 2,
+                actual: 3
+            }))
+        ));
+    }
+
+    #[test]
+    fn drop_requests_when_choked() {
+        let mut session = PeerSession::new(10);
+        session.handle(PeerMessage::Unchoke).unwrap();
+        for begin in [200, 0, 100] {
+            session.request(block(1, begin));
+        }
+        assert_eq!(
+            Some(PeerEvent::Block {
+                block: block(1, 100),
+                data: Bytes::from(vec![0; 100]),
+            }),
+            session.handle(piece(block(1, 100))).unwrap()
+        );
+        assert_eq!(
+            Some(PeerEvent::Choked {
+                dropped: vec![block(1, 0), block(1, 200)],
+            }),
+            session.handle(PeerMessage::Choke).unwrap()
+        );
+        assert!(session.peer_choking());
+        assert!(session.requests().is_empty());
+        // Answers to dropped requests are ignored.
+        assert_eq!(None, session.handle(piece(block(1, 0))).unwrap());
+    }
+
+    #[test]
+    fn track_requests() {
+        let mut session = PeerSession::new(10);
+        session.request(block(2, 0));
+        session.request(block(2, 100));
+        assert!(session.cancel(block(2, 100)).is_some());
+        assert!(session.cancel(block(2, 100)).is_none());
+        assert_eq!(None, session.handle(piece(block(2, 100))).unwrap());
+
+        let short = PeerMessage::Piece {
+            index: 2,
+            begin: 0,
+            block: Bytes::from_static(b"short"),
+        };
+        assert!(matches!(
+            session.handle(short),
+            Err(DownloadError::MalformedMessage(MessageTag::Piece))
+        ));
+    }
+
+    #[test]
+    fn track_interest_and_choking() {
+        let mut session = PeerSession::new(10);
+        assert!(session.am_choking() && !session.am_interested());
+        assert_eq!(Some(PeerMessage::Interested), session.set_interested(true));
+        assert_eq!(None, session.set_interested(true));
+        assert!(session.am_interested());
+
+        let request = PeerMessage::Request {
+            index: 1,
+            begin: 0,
+            length: 100,
+        };
+        assert_eq!(None, session.handle(request.clone()).unwrap());
+        assert_eq!(Some(PeerMessage::Unchoke), session.set_choking(false));
+        assert_eq!(
+            Some(PeerEvent::Request(block(1, 0))),
+            session.handle(request).unwrap()
+        );
+
+        assert_eq!(
+            Some(PeerEvent::Interested),
+            session.handle(PeerMessage::Interested).unwrap()
+        );
+        assert!(session.peer_interested());
+        session.handle(PeerMessage::NotInterested).unwrap();
+        assert!(!session.peer_interested());
+    }
+}
@@ -0,0 +1,209 @@
+//! A seeder serving pieces over loopback, for exercising downloads end to end.
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use sha1::{Digest, Sha1};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_util::codec::Framed;
+
+use super::BLOCK_MAX;
+use crate::peer::{Bitfield, Handshake, MessageFramer, PeerMessage};
+use crate::peer_id::PeerId;
+use crate::random::Rng;
+use crate::torrent::{File, Hashes, Info, Keys};
+
+/// Deterministic contents for a torrent of `len` bytes.
+pub fn data(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 % 251) as u8).collect()
+}
+
+/// A torrent named `dir` holding `data` in `a.bin`, its first 40000 bytes, and `sub/b.bin`.
+pub fn multi_file_torrent(data: &[u8], piece_length: usize) -> Info {
+    let pieces = data
+        .chunks(piece_length)
+        .map(|piece| Sha1::digest(piece).into())
+        .collect();
+    Info {
+        name: "dir".into(),
+        piece_length,
+        pieces: Hashes(pieces),
+        keys: Keys::MultiFile {
+            files: vec![
+                File {
+                    length: 40_000,
+                    path: vec!["a.bin".into()],
+                },
+                File {
+                    length: data.len() - 40_000,
+                    path: vec!["sub".into(), "b.bin".into()],
+                },
+            ],
+        },
+    }
+}
+
+pub fn single_file_torrent(data: &[u8], piece_length: usize) -> Info {
+    Info {
+        keys: Keys::SingleFile { length: data.len() },
+        ..multi_file_torrent(data, piece_length)
+    }
+}
+
+/// Serves `data` to every peer that connects and unchokes them as soon as they are interested.
+#[derive(Debug, Clone)]
+pub struct FakeSeeder {
+    pub info_hash: [u8; 20],
+    pub piece_length: usize,
+    pub num_pieces: usize,
+    pub data: Vec<u8>,
+    /// Pieces announced in the bitfield.
+    pub has: HashSet<usize>,
+    /// Announces pieces with `have` messages after unchoking, instead of in a bitfield.
+    pub announce_with_have: bool,
+    /// Keeps every peer choked.
+    pub never_unchoke: bool,
+    /// Pieces whose blocks are all sent with a flipped byte.
+    pub corrupt: HashSet<usize>,
+    /// Pieces whose requests are never answered.
+    pub stall: HashSet<usize>,
+    /// Number of blocks sent before the connection is closed.
+    pub disconnect_after: Option<usize>,
+    /// Delay before the handshake and each block are sent, as on a link with this much latency.
+    pub latency: Duration,
+    /// Random extra delay of up to this much per block, so blocks arrive out of order.
+    pub jitter: Duration,
+    /// Number of blocks sent, over all connections.
+    pub served: Arc<AtomicUsize>,
+    /// Number of `cancel` messages received, over all connections.
+    pub cancelled: Arc<AtomicUsize>,
+}
+
+impl FakeSeeder {
+    /// A seeder that has every piece of `info` and sends them intact and without delay.
+    pub fn new(info: &Info, info_hash: [u8; 20], data: Vec<u8>) -> Self {
+        let num_pieces = info.pieces.0.len();
+        Self {
+            info_hash,
+            piece_length: info.piece_length,
+            num_pieces,
+            data,
+            has: (0..num_pieces).collect(),
+            announce_with_have: false,
+            never_unchoke: false,
+            corrupt: HashSet::new(),
+            stall: HashSet::new(),
+            disconnect_after: None,
+            latency: Duration::ZERO,
+            jitter: Duration::ZERO,
+            served: Arc::default(),
+            cancelled: Arc::default(),
+        }
+    }
+
+    /// Starts accepting connections in the background.
+    pub async fn spawn(self) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(self.clone().serve(stream));
+            }
+        });
+        addr
+    }
+
+    async fn serve(self, mut stream: tokio::net::TcpStream) {
+        if Handshake::read(&mut stream).await.is_err() {
+            return;
+        }
+        tokio::time::sleep(self.latency).await;
+        let handshake = Handshake::new(self.info_hash, PeerId(*b"-XX0001-fakeseeder00"));
+        if handshake.write(&mut stream).await.is_err() {
+            return;
+        }
+
+        let framer = MessageFramer::for_torrent(self.num_pieces, BLOCK_MAX);
+        // Blocks are handed to a writer so they can be delayed independently of each other.
+        let (mut sink, mut stream) = Framed::new(stream, framer).split();
+        let (outgoing, mut outgoing_rx) = mpsc::unbounded_channel::<PeerMessage>();
+        tokio::spawn(async move {
+            while let Some(message) = outgoing_rx.recv().await {
+                if sink.send(message).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        if !self.announce_with_have {
+            let mut bitfield = Bitfield::new(self.num_pieces);
+            for &index in &self.has {
+                bitfield.set(index);
+            }
+            let _ = outgoing.send(PeerMessage::Bitfield(bitfield));
+        }
+
+        let mut rng = Rng::new();
+        let mut sent = 0;
+        while let Some(Ok(request)) = stream.next().await {
+            match request {
+                PeerMessage::Interested if !self.never_unchoke => {
+                    let _ = outgoing.send(PeerMessage::Unchoke);
+                    if self.announce_with_have {
+                        for &index in &self.has {
+                            let _ = outgoing.send(PeerMessage::KeepAlive);
+                            let _ = outgoing.send(PeerMessage::Have(index as u32));
+                        }
+                    }
+                }
+                PeerMessage::Request {
+                    index,
+                    begin,
+                    length,
+                } => {
+                    if self.stall.contains(&(index as usize)) {
+                        continue;
+                    }
+                    if self.disconnect_after == Some(sent) {
+                        return;
+                    }
+                    sent += 1;
+                    self.served.fetch_add(1, Ordering::Relaxed);
+                    let start = index as usize * self.piece_length + begin as usize;
+                    let mut block = self.data[start..start + length as usize].to_vec();
+                    if self.corrupt.contains(&(index as usize)) {
+                        block[0] ^= 0xff;
+                    }
+                    let piece = PeerMessage::Piece {
+                        index,
+                        begin,
+                        block: Bytes::from(block),
+                    };
+
+                    let jitter = self.jitter.as_micros() as usize;
+                    let delay = self.latency + Duration::from_micros(rng.below(jitter + 1) as u64);
+                    if delay.is_zero() {
+                        let _ = outgoing.send(piece);
+                    } else {
+                        let outgoing = outgoing.clone();
+                        tokio::spawn(async move {
+                            tokio::time::sleep(delay).await;
+                            let _ = outgoing.send(piece);
+                        });
+                    }
+                }
+                PeerMessage::Cancel { .. } => {
+                    self.cancelled.fetch_add(1, Ordering::Relaxed);
+                }
+                _ => {}
+            }
+        }
+    }
+}
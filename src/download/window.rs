@@ -0,0 +1,111 @@
+//! Sizing of the request pipeline.
+
+use std::time::Duration;
+
+use super::BLOCK_MAX;
+
+/// Weight of the newest sample in the smoothed download rate.
+const RATE_SMOOTHING: f64 = 0.25;
+
+/// Number of block requests kept in flight to a peer.
+///
+/// Like libtorrent's request queue, the window holds as many blocks as the peer delivers in
+/// `queue_time` at its observed rate, so slow peers aren't flooded and fast ones are never idle
+/// waiting for the next request.
+#[derive(Debug, Clone)]
+pub struct RequestWindow {
+    min: usize,
+    max: usize,
+    queue_time: Duration,
+    size: usize,
+    /// Smoothed download rate in bytes per second, `None` until the first block arrives.
+    rate: Option<f64>,
+}
+
+impl Default for RequestWindow {
+    /// Starts at 4 requests and grows up to 250, keeping 3 seconds worth of blocks queued.
+    fn default() -> Self {
+        Self::new(4, 250, Duration::from_secs(3))
+    }
+}
+
+impl RequestWindow {
+    /// A window that starts at `min` requests and adapts between `min` and `max`.
+    pub fn new(min: usize, max: usize, queue_time: Duration) -> Self {
+        assert!(
+            0 < min && min <= max,
+            "window bounds must satisfy 0 < min <= max"
+        );
+        Self {
+            min,
+            max,
+            queue_time,
+            size: min,
+            rate: None,
+        }
+    }
+
+    /// A window that always keeps `size` requests in flight.
+    pub fn fixed(size: usize) -> Self {
+        Self::new(size, size, Duration::ZERO)
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Observed download rate in bytes per second.
+    pub fn rate(&self) -> Option<f64> {
+        self.rate
+    }
+
+    /// Records that `bytes` arrived `elapsed` after the previous block, and resizes the window.
+    pub fn record(&mut self, bytes: usize, elapsed: Duration) {
+        // Back-to-back blocks can arrive within the timer's resolution.
+        let sample = bytes as f64 / elapsed.as_secs_f64().max(1e-6);
+        let rate = match self.rate {
+            Some(rate) => rate + RATE_SMOOTHING * (sample - rate),
+            None => sample,
+        };
+        self.rate = Some(rate);
+        let wanted = rate * self.queue_time.as_secs_f64() / BLOCK_MAX as f64;
+        self.size = (wanted.ceil() as usize).clamp(self.min, self.max);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grow_with_throughput() {
+        let mut window = RequestWindow::new(2, 100, Duration::from_secs(1));
+        assert_eq!(2, window.size());
+        // 10 blocks per second fill a window of 10.
+        window.record(BLOCK_MAX, Duration::from_millis(100));
+        assert_eq!(10, window.size());
+        for _ in 0..50 {
+            window.record(BLOCK_MAX, Duration::from_millis(1));
+        }
+        assert_eq!(100, window.size());
+    }
+
+    #[test]
+    fn shrink_for_slow_peers() {
+        let mut window = RequestWindow::new(2, 100, Duration::from_secs(1));
+        window.record(BLOCK_MAX, Duration::from_millis(10));
+        assert_eq!(100, window.size());
+        for _ in 0..50 {
+            window.record(BLOCK_MAX, Duration::from_secs(2));
+        }
+        assert_eq!(2, window.size());
+        assert!(window.rate().unwrap() < BLOCK_MAX as f64);
+    }
+
+    #[test]
+    fn fixed_window() {
+        let mut window = RequestWindow::fixed(1);
+        window.record(BLOCK_MAX, Duration::from_micros(1));
+        assert_eq!(1, window.size());
+    }
+}
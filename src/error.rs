@@ -1,10 +1,63 @@
 use thiserror::Error;
 
+use crate::bencode::DecodeError;
+use crate::download::DownloadError;
+use crate::peer::{FrameError, HandshakeError};
+use crate::sanitize::PathError;
+use crate::tracker::udp::UdpTrackerError;
+use crate::tracker::TrackerError;
+
 #[derive(Error, Debug)]
 pub enum BittorrentError {
     #[error(transparent)]
     IOError(#[from] std::io::Error),
 
-    #[error("Bencode error")]
-    BencodeError(#[from] serde_bencode::Error),
+    #[error("Malformed bencode: {0}")]
+    DecodeError(#[from] DecodeError),
+
+    #[error("Unsafe path in torrent: {0}")]
+    PathError(#[from] PathError),
+
+    #[error("HTTP error")]
+    HttpError(#[from] reqwest::Error),
+
+    #[error("URL encoding error")]
+    UrlEncodeError(#[from] serde_urlencoded::ser::Error),
+
+    #[error("UDP tracker error: {0}")]
+    UdpTrackerError(#[from] UdpTrackerError),
+
+    #[error("Malformed torrent: {0}")]
+    MalformedTorrent(&'static str),
+
+    #[error("Torrent has no info dictionary")]
+    MissingInfo,
+
+    #[error("Piece {index} is {actual} bytes long instead of {expected}")]
+    PieceLength {
+        index: usize,
+        expected: usize,
+        actual: usize,
+    },
+
+    #[error("Torrent has no trackers")]
+    NoTrackers,
+
+    #[error("Tracker {0} did not respond in time")]
+    TrackerTimeout(String),
+
+    #[error("Tracker {0} does not support scraping")]
+    ScrapeUnsupported(String),
+
+    #[error("Tracker error: {0}")]
+    TrackerError(#[from] TrackerError),
+
+    #[error("Handshake error: {0}")]
+    HandshakeError(#[from] HandshakeError),
+
+    #[error("Peer protocol error: {0}")]
+    FrameError(#[from] FrameError),
+
+    #[error("Download error: {0}")]
+    DownloadError(#[from] DownloadError),
 }
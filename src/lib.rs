@@ -1,5 +1,10 @@
 pub mod bencode;
+pub mod download;
 pub mod error;
 pub mod peer;
+pub mod peer_id;
+mod random;
+pub mod sanitize;
+pub mod storage;
 pub mod torrent;
 pub mod tracker;
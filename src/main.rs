@@ -1,17 +1,17 @@
-use std::{net::SocketAddrV4, path::PathBuf};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, time::Duration};
 
 use anyhow::Context;
 use bittorrent::{
-    bencode::decode_bencoded_value,
-    peer::{Handshake, Message, MessageFramer, MessageTag, Piece, Request},
+    bencode,
+    download::{self, DownloadConfig, PeerTimeouts},
+    peer::Handshake,
+    peer_id::PeerId,
+    storage::Storage,
     torrent::{Keys, Torrent},
-    tracker::{urlencode, TrackerRequest, TrackerResponse},
+    tracker::{self, TrackerRequest, TrackerTiers},
 };
 use clap::{Parser, Subcommand};
-use futures_util::{SinkExt, StreamExt};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-
-const BLOCK_MAX: usize = 1 << 14;
+use tokio::time::timeout;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -36,6 +36,18 @@ enum Command {
         torrent: PathBuf,
         peer: String,
     },
+    Scrape {
+        #[arg(required = true)]
+        torrents: Vec<PathBuf>,
+    },
+    Download {
+        #[arg(short)]
+        output: PathBuf,
+        torrent: PathBuf,
+        /// Seconds to keep going without a connected peer or a newly verified piece.
+        #[arg(long)]
+        stall_timeout: Option<u64>,
+    },
     DownloadPiece {
         #[arg(short)]
         output: PathBuf,
@@ -44,22 +56,62 @@ enum Command {
     },
 }
 
+/// Announces to the torrent's trackers, reporting the responding one's warning, and returns
+/// its peers.
+async fn find_peers(
+    torrent: &Torrent,
+    request: &TrackerRequest,
+) -> anyhow::Result<Vec<SocketAddr>> {
+    let mut tiers = TrackerTiers::new(torrent.tracker_tiers());
+    let (url, response) = tiers
+        .announce(&torrent.info_hash(), request)
+        .await
+        .context("query trackers")?;
+    if let Some(warning) = &response.warning_message {
+        eprintln!("Tracker {url} warns: {warning}");
+    }
+    Ok(tracker::merge_peers([&response])
+        .into_iter()
+        .map(|peer| peer.addr)
+        .collect())
+}
+
+/// Prints a remote peer's ID, along with its client when it can be recognised.
+fn print_remote_peer_id(peer_id: PeerId) {
+    println!("Peer ID: {}", hex::encode(peer_id.as_bytes()));
+    if let Some(client) = peer_id.client() {
+        println!("Client: {client}");
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
+    // One ID for the whole session, shared by tracker announces and handshakes.
+    let peer_id = PeerId::generate();
     match args.command {
         Command::Decode { value } => {
-            let (decoded_value, _) = decode_bencoded_value(&value);
-            // let decoded_value: serde_json::Value = serde_bencode::from_str(&value).unwrap();
-            println!("{}", decoded_value);
+            let decoded_value = bencode::decode(value.as_bytes()).context("decode value")?;
+            println!("{}", decoded_value.to_json());
         }
         Command::Info { torrent } => {
             let torrent = Torrent::from_file(torrent).context("open torrent file")?;
-            println!("Tracker URL: {}", torrent.announce);
-            if let Keys::SingleFile { length } = torrent.info.keys {
-                println!("Length: {}", length);
-            } else {
-                todo!();
+            let tiers = torrent.tracker_tiers();
+            if let Some(url) = tiers.first().and_then(|tier| tier.first()) {
+                println!("Tracker URL: {}", url);
+            }
+            if tiers.iter().flatten().count() > 1 {
+                println!("Trackers:");
+                for (i, tier) in tiers.iter().enumerate() {
+                    println!("Tier {}: {}", i + 1, tier.join(" "));
+                }
+            }
+            println!("Length: {}", torrent.info.total_length());
+            if let Keys::MultiFile { files } = &torrent.info.keys {
+                println!("Files:");
+                for file in files {
+                    println!("{} ({} bytes)", file.path.join("/"), file.length);
+                }
             }
 
             println!("Info Hash: {}", hex::encode(torrent.info_hash()));
@@ -71,66 +123,114 @@ async fn main() -> anyhow::Result<()> {
         }
         Command::Peers { torrent } => {
             let torrent = Torrent::from_file(torrent).context("open torrent file")?;
-            let length = if let Keys::SingleFile { length } = torrent.info.keys {
-                println!("Length: {}", length);
-                length
-            } else {
-                todo!();
-            };
-
-            let request = TrackerRequest {
-                peer_id: "00112233445566778899".into(),
-                port: 6881,
-                uploaded: 0,
-                downloaded: 0,
-                left: length,
-                compact: 1,
-            };
-
-            // Info hash of the torrent, 20 bytes long, URL encoded.
-            let info_hash = torrent.info_hash();
+            let length = torrent.info.total_length();
+            println!("Length: {}", length);
 
-            let url_params =
-                serde_urlencoded::to_string(&request).context("url-encode tracker parameters")?;
-            let tracker_url = format!(
-                "{}?{}&info_hash={}",
-                torrent.announce,
-                url_params,
-                &urlencode(&info_hash)
-            );
-            let response = reqwest::get(tracker_url).await.context("query tracker")?;
-            let response = response.bytes().await.context("fetch tracker response")?;
-            let response: TrackerResponse =
-                serde_bencode::from_bytes(&response).context("parse tracker response")?;
+            let request = TrackerRequest::new(peer_id, 6881, length);
 
-            for peer in response.peers.0 {
-                println!("{}:{}", peer.ip(), peer.port());
+            let peers = find_peers(&torrent, &request).await?;
+            for peer in peers {
+                println!("{}", peer);
             }
         }
         Command::Handshake { torrent, peer } => {
             let torrent = Torrent::from_file(torrent).context("open torrent file")?;
             let info_hash = torrent.info_hash();
 
-            let peer = peer.parse::<SocketAddrV4>().context("parse peer address")?;
-            let mut peer = tokio::net::TcpStream::connect(peer)
+            let peer = peer.parse::<SocketAddr>().context("parse peer address")?;
+            let timeouts = PeerTimeouts::default();
+            let mut peer = timeout(timeouts.connect, tokio::net::TcpStream::connect(peer))
                 .await
+                .context("connect to peer")?
                 .context("connect to peer")?;
 
-            let mut handshake = Handshake::new(info_hash, *b"00112233445566778899");
-            let handshake_bytes = handshake.as_bytes_mut();
-
-            peer.write_all(handshake_bytes)
+            let handshake = Handshake::new(info_hash, peer_id);
+            let handshake = timeout(timeouts.handshake, handshake.exchange(&mut peer))
                 .await
-                .context("write handshake")?;
+                .context("exchange handshakes")?
+                .context("exchange handshakes")?;
 
-            peer.read_exact(handshake_bytes)
-                .await
-                .context("read handshake")?;
+            print_remote_peer_id(handshake.peer_id);
+        }
+        Command::Scrape { torrents } => {
+            let torrents = torrents
+                .into_iter()
+                .map(|path| {
+                    Torrent::from_file(path.clone())
+                        .with_context(|| format!("open torrent file {}", path.display()))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let info_hashes: Vec<[u8; 20]> = torrents.iter().map(Torrent::info_hash).collect();
+
+            // Scrape each tracker once for every torrent that lists it and still lacks stats.
+            let mut stats = HashMap::new();
+            let mut urls: Vec<String> = Vec::new();
+            for torrent in &torrents {
+                for url in torrent.tracker_tiers().into_iter().flatten() {
+                    if !urls.contains(&url) {
+                        urls.push(url);
+                    }
+                }
+            }
+            for url in urls {
+                let pending: Vec<[u8; 20]> = torrents
+                    .iter()
+                    .zip(&info_hashes)
+                    .filter(|(torrent, info_hash)| {
+                        !stats.contains_key(*info_hash)
+                            && torrent.tracker_tiers().iter().flatten().any(|u| *u == url)
+                    })
+                    .map(|(_, info_hash)| *info_hash)
+                    .collect();
+                if pending.is_empty() {
+                    continue;
+                }
+                match tracker::scrape(&url, &pending).await {
+                    Ok(response) => stats.extend(response),
+                    Err(e) => eprintln!("Scraping {url} failed: {:#}", anyhow::Error::from(e)),
+                }
+            }
 
-            assert_eq!(handshake.length, 19);
-            assert_eq!(&handshake.bittorrent, b"BitTorrent protocol");
+            for (torrent, info_hash) in torrents.iter().zip(&info_hashes) {
+                match stats.get(info_hash) {
+                    Some(s) => println!(
+                        "{} {}: seeders {}, leechers {}, downloaded {}",
+                        hex::encode(info_hash),
+                        torrent.info.name,
+                        s.complete,
+                        s.incomplete,
+                        s.downloaded
+                    ),
+                    None => println!(
+                        "{} {}: unavailable",
+                        hex::encode(info_hash),
+                        torrent.info.name
+                    ),
+                }
+            }
+        }
+        Command::Download {
+            output,
+            torrent,
+            stall_timeout,
+        } => {
+            let torrent = Torrent::from_file(torrent).context("open torrent file")?;
+            let storage =
+                Storage::new(torrent.info.clone(), &output).context("prepare output files")?;
+            for (path, changes) in storage.changes() {
+                for change in changes {
+                    eprintln!("{}: {change}", path.display());
+                }
+            }
 
-            println!("Peer ID: {}", hex::encode(handshake.peer_id));
+            let mut config = DownloadConfig::default();
+            if let Some(seconds) = stall_timeout {
+                config.stall_timeout = Duration::from_secs(seconds);
+            }
+            download::download(&torrent, &storage, peer_id, config)
+                .await
+                .with_context(|| format!("download {}", torrent.info.name))?;
+            println!("Downloaded {} to {}.", torrent.info.name, output.display());
         }
         Command::DownloadPiece {
             output,
@@ -138,131 +238,21 @@ async fn main() -> anyhow::Result<()> {
             piece,
         } => {
             let torrent = Torrent::from_file(torrent).context("open torrent file")?;
-
-            assert!(piece < torrent.info.pieces.0.len());
-
-            let length = if let Keys::SingleFile { length } = torrent.info.keys {
-                println!("Length: {}", length);
-                length
-            } else {
-                todo!();
-            };
-
-            let request = TrackerRequest {
-                peer_id: "00112233445566778899".into(),
-                port: 6881,
-                uploaded: 0,
-                downloaded: 0,
-                left: length,
-                compact: 1,
-            };
-
-            // Info hash of the torrent, 20 bytes long, URL encoded.
-            let info_hash = torrent.info_hash();
-
-            let url_params =
-                serde_urlencoded::to_string(&request).context("url-encode tracker parameters")?;
-            let tracker_url = format!(
-                "{}?{}&info_hash={}",
-                torrent.announce,
-                url_params,
-                &urlencode(&info_hash)
-            );
-            let response = reqwest::get(tracker_url).await.context("query tracker")?;
-            let response = response.bytes().await.context("fetch tracker response")?;
-            let tracker_info: TrackerResponse =
-                serde_bencode::from_bytes(&response).context("parse tracker response")?;
-
-            let peer = tracker_info.peers.0[0];
-
-            let mut peer = tokio::net::TcpStream::connect(peer)
-                .await
-                .context("connect to peer")?;
-
-            let mut handshake = Handshake::new(info_hash, *b"00112233445566778899");
-            let handshake_bytes = handshake.as_bytes_mut();
-
-            peer.write_all(handshake_bytes)
-                .await
-                .context("write handshake")?;
-
-            peer.read_exact(handshake_bytes)
-                .await
-                .context("read handshake")?;
-
-            assert_eq!(handshake.length, 19);
-            assert_eq!(&handshake.bittorrent, b"BitTorrent protocol");
-
-            println!("Peer ID: {}", hex::encode(handshake.peer_id));
-
-            let mut peer = tokio_util::codec::Framed::new(peer, MessageFramer {});
-            let bitfield = peer
-                .next()
-                .await
-                .expect("peer always sends bitfields")
-                .context("peer message was invalid")?;
-
-            assert_eq!(bitfield.tag, MessageTag::Bitfield);
-
-            peer.send(Message {
-                tag: MessageTag::Interested,
-                payload: Vec::new(),
-            })
+            let length = torrent.info.total_length();
+            let request = TrackerRequest::new(peer_id, 6881, length);
+            let peers = find_peers(&torrent, &request).await?;
+
+            download::download_piece(
+                &torrent.info,
+                torrent.info_hash(),
+                piece,
+                &peers,
+                peer_id,
+                &output,
+            )
             .await
-            .context("send interested message")?;
-
-            let unchoke = peer
-                .next()
-                .await
-                .expect("peer always sends unchoke")
-                .context("peer message was invalid")?;
-
-            assert_eq!(unchoke.tag, MessageTag::Unchoke);
-            assert!(unchoke.payload.is_empty());
-
-            let piece_index = piece;
-            let piece_hash = torrent.info.pieces.0[piece_index];
-            let piece_size = if piece_index == torrent.info.pieces.0.len() + 1 {
-                length % torrent.info.piece_length
-            } else {
-                torrent.info.piece_length
-            };
-
-            let mut all_blocks: Vec<u8> = Vec::with_capacity(piece_size);
-            let num_blocks = (piece_size / (BLOCK_MAX - 1)) / BLOCK_MAX;
-            for block in 0..num_blocks {
-                let block_size = if block == num_blocks - 1 {
-                    piece_size % BLOCK_MAX
-                } else {
-                    BLOCK_MAX
-                };
-                let mut request = Request::new(
-                    piece_index as u32,
-                    (block * BLOCK_MAX) as u32,
-                    block_size as u32,
-                );
-                let request_bytes = Vec::from(request.as_bytes_mut());
-                peer.send(Message {
-                    tag: MessageTag::Request,
-                    payload: request_bytes,
-                })
-                .await
-                .with_context(|| format!("send request for block {block}"))?;
-
-                let piece = peer
-                    .next()
-                    .await
-                    .expect("peer always sends piece")
-                    .context("peer message was invalid")?;
-
-                assert_eq!(piece.tag, MessageTag::Piece);
-                assert!(!piece.payload.is_empty());
-
-                let piece = &(piece.payload[..]) as *const [u8] as *const Piece;
-                let piece = unsafe { &*piece };
-
-                all_blocks.extend(piece.block());
-            }
+            .with_context(|| format!("download piece {piece}"))?;
+            println!("Piece {piece} downloaded to {}.", output.display());
         }
     }
     Ok(())
@@ -1,37 +1,154 @@
-use std::io::Read;
-
-use bytes::{Buf, BufMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio_util::codec::{Decoder, Encoder};
 
-#[repr(C)]
+use crate::error::BittorrentError;
+use crate::peer_id::PeerId;
+
+/// Protocol string every handshake starts with, after its length.
+const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+
+/// Length of a handshake on the wire.
+pub const HANDSHAKE_LEN: usize = 68;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum HandshakeError {
+    #[error("peer does not speak the BitTorrent protocol")]
+    InvalidProtocol,
+    #[error("peer is serving a different torrent")]
+    InfoHashMismatch,
+}
+
+/// Protocol extensions a peer advertises in the reserved bytes of its handshake.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities(pub [u8; 8]);
+
+impl Capabilities {
+    /// BEP 10 extension protocol.
+    const EXTENSION_PROTOCOL: (usize, u8) = (5, 0x10);
+    /// BEP 6 Fast extension.
+    const FAST: (usize, u8) = (7, 0x04);
+    /// BEP 5 DHT, announced with `port` messages.
+    const DHT: (usize, u8) = (7, 0x01);
+    /// BEP 52 v2 torrents, for upgrading hybrid torrents.
+    const V2: (usize, u8) = (7, 0x10);
+
+    pub fn extension_protocol(&self) -> bool {
+        self.get(Self::EXTENSION_PROTOCOL)
+    }
+
+    pub fn set_extension_protocol(&mut self, enabled: bool) {
+        self.set(Self::EXTENSION_PROTOCOL, enabled);
+    }
+
+    pub fn fast(&self) -> bool {
+        self.get(Self::FAST)
+    }
+
+    pub fn set_fast(&mut self, enabled: bool) {
+        self.set(Self::FAST, enabled);
+    }
+
+    pub fn dht(&self) -> bool {
+        self.get(Self::DHT)
+    }
+
+    pub fn set_dht(&mut self, enabled: bool) {
+        self.set(Self::DHT, enabled);
+    }
+
+    pub fn v2(&self) -> bool {
+        self.get(Self::V2)
+    }
+
+    pub fn set_v2(&mut self, enabled: bool) {
+        self.set(Self::V2, enabled);
+    }
+
+    fn get(&self, (byte, mask): (usize, u8)) -> bool {
+        self.0[byte] & mask != 0
+    }
+
+    fn set(&mut self, (byte, mask): (usize, u8), enabled: bool) {
+        if enabled {
+            self.0[byte] |= mask;
+        } else {
+            self.0[byte] &= !mask;
+        }
+    }
+}
+
+/// The first message each side sends, naming the torrent and the peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Handshake {
-    pub length: u8,
-    pub bittorrent: [u8; 19],
-    pub reserved: [u8; 8],
+    pub capabilities: Capabilities,
     pub info_hash: [u8; 20],
-    pub peer_id: [u8; 20],
+    pub peer_id: PeerId,
 }
 
 impl Handshake {
-    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+    /// A handshake advertising no extensions.
+    pub fn new(info_hash: [u8; 20], peer_id: PeerId) -> Self {
         Self {
-            length: 19,
-            bittorrent: *b"BitTorrent protocol",
-            reserved: [0; 8],
+            capabilities: Capabilities::default(),
             info_hash,
             peer_id,
         }
     }
 
-    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
-        let bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()];
-        let bytes: &mut [u8; std::mem::size_of::<Self>()] = unsafe { &mut *bytes };
+    pub fn to_bytes(&self) -> [u8; HANDSHAKE_LEN] {
+        let mut bytes = [0; HANDSHAKE_LEN];
+        bytes[0] = PROTOCOL.len() as u8;
+        bytes[1..20].copy_from_slice(PROTOCOL);
+        bytes[20..28].copy_from_slice(&self.capabilities.0);
+        bytes[28..48].copy_from_slice(&self.info_hash);
+        bytes[48..].copy_from_slice(self.peer_id.as_bytes());
         bytes
     }
+
+    pub fn from_bytes(bytes: &[u8; HANDSHAKE_LEN]) -> Result<Self, HandshakeError> {
+        if bytes[0] as usize != PROTOCOL.len() || &bytes[1..20] != PROTOCOL {
+            return Err(HandshakeError::InvalidProtocol);
+        }
+        Ok(Self {
+            capabilities: Capabilities(bytes[20..28].try_into().expect("length 8")),
+            info_hash: bytes[28..48].try_into().expect("length 20"),
+            peer_id: PeerId(bytes[48..].try_into().expect("length 20")),
+        })
+    }
+
+    /// Reads the other side's handshake.
+    pub async fn read(reader: &mut (impl AsyncRead + Unpin)) -> Result<Self, BittorrentError> {
+        let mut bytes = [0; HANDSHAKE_LEN];
+        reader.read_exact(&mut bytes).await?;
+        Ok(Self::from_bytes(&bytes)?)
+    }
+
+    pub async fn write(
+        &self,
+        writer: &mut (impl AsyncWrite + Unpin),
+    ) -> Result<(), BittorrentError> {
+        writer.write_all(&self.to_bytes()).await?;
+        Ok(())
+    }
+
+    /// Sends this handshake and returns the peer's, which must be for the same torrent.
+    pub async fn exchange(
+        &self,
+        stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    ) -> Result<Self, BittorrentError> {
+        self.write(stream).await?;
+        let theirs = Self::read(stream).await?;
+        if theirs.info_hash != self.info_hash {
+            return Err(HandshakeError::InfoHashMismatch.into());
+        }
+        Ok(theirs)
+    }
 }
 
 #[repr(u8)]
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MessageTag {
     Choke = 0,
     Unchoke = 1,
@@ -42,10 +159,11 @@ pub enum MessageTag {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    Port = 9,
 }
 
 impl TryFrom<u8> for MessageTag {
-    type Error = std::io::Error;
+    type Error = FrameError;
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
         match value {
@@ -58,56 +176,334 @@ impl TryFrom<u8> for MessageTag {
             6 => Ok(MessageTag::Request),
             7 => Ok(MessageTag::Piece),
             8 => Ok(MessageTag::Cancel),
-            _ => Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Unknown message type {}.", value),
-            )),
+            9 => Ok(MessageTag::Port),
+            _ => Err(FrameError::UnknownMessage(value)),
         }
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct Message {
-    pub tag: MessageTag,
-    pub payload: Vec<u8>,
+#[derive(Error, Debug)]
+pub enum FrameError {
+    #[error("frame of {length} bytes exceeds the limit of {max}")]
+    TooLarge { length: usize, max: usize },
+    #[error("unknown message type {0}")]
+    UnknownMessage(u8),
+    #[error("{tag:?} message has a payload of invalid length {length}")]
+    Malformed { tag: MessageTag, length: usize },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum BitfieldError {
+    #[error("bitfield is {actual} bytes long instead of {expected}")]
+    WrongLength { expected: usize, actual: usize },
+    #[error("bitfield has bits set past the last piece")]
+    SpareBits,
 }
 
-pub struct MessageFramer {}
+/// A set of pieces, one bit per piece starting from the high bit of the first byte, as sent in
+/// `bitfield` messages.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    num_pieces: usize,
+}
 
-const MAX: usize = 1 << 16;
+impl Bitfield {
+    /// An empty set for a torrent of `num_pieces` pieces.
+    pub fn new(num_pieces: usize) -> Self {
+        Self {
+            bytes: vec![0; num_pieces.div_ceil(8)],
+            num_pieces,
+        }
+    }
 
-impl Encoder<Message> for MessageFramer {
-    type Error = std::io::Error;
+    /// Checks a bitfield received from a peer against the number of pieces in the torrent.
+    pub fn from_bytes(bytes: &[u8], num_pieces: usize) -> Result<Self, BitfieldError> {
+        let expected = num_pieces.div_ceil(8);
+        if bytes.len() != expected {
+            return Err(BitfieldError::WrongLength {
+                expected,
+                actual: bytes.len(),
+            });
+        }
+        let spare = expected * 8 - num_pieces;
+        if bytes
+            .last()
+            .is_some_and(|last| last & ((1 << spare) - 1) != 0)
+        {
+            return Err(BitfieldError::SpareBits);
+        }
+        Ok(Self {
+            bytes: bytes.to_vec(),
+            num_pieces,
+        })
+    }
 
-    fn encode(&mut self, item: Message, dst: &mut bytes::BytesMut) -> Result<(), Self::Error> {
-        // Don't send a message if it is longer than the other end will accept.
-        if item.payload.len() + 1 > MAX {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Frame of length {} is too large.", item.payload.len()),
-            ));
+    /// A bitfield as it arrived, before the number of pieces is known.
+    fn from_payload(bytes: Vec<u8>) -> Self {
+        Self {
+            num_pieces: bytes.len() * 8,
+            bytes,
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.num_pieces
+    }
+
+    pub fn has(&self, index: usize) -> bool {
+        index < self.num_pieces && self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    /// Adds the piece at `index`, which must be within the torrent.
+    pub fn set(&mut self, index: usize) {
+        assert!(index < self.num_pieces, "piece {index} out of range");
+        self.bytes[index / 8] |= 0x80 >> (index % 8);
+    }
+
+    /// Number of pieces in the set.
+    pub fn count(&self) -> usize {
+        self.bytes
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+
+    /// Indices of the pieces in the set, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.num_pieces).filter(|&index| self.has(index))
+    }
+
+    /// Adds every piece of `other`.
+    pub fn union_with(&mut self, other: &Bitfield) {
+        assert_eq!(
+            self.num_pieces, other.num_pieces,
+            "bitfields of different torrents"
+        );
+        for (byte, theirs) in self.bytes.iter_mut().zip(&other.bytes) {
+            *byte |= theirs;
+        }
+    }
+
+    /// Pieces in this set but not in `other`, such as those a peer has that we still need.
+    pub fn difference(&self, other: &Bitfield) -> Bitfield {
+        assert_eq!(
+            self.num_pieces, other.num_pieces,
+            "bitfields of different torrents"
+        );
+        Self {
+            bytes: self
+                .bytes
+                .iter()
+                .zip(&other.bytes)
+                .map(|(ours, theirs)| ours & !theirs)
+                .collect(),
+            num_pieces: self.num_pieces,
         }
+    }
+}
+
+/// A message of the peer wire protocol, with its payload parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerMessage {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Bitfield),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Bytes,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// The port the peer's DHT node listens on.
+    Port(u16),
+}
 
-        // Convert the length into a byte array.
-        let len_slice = u32::to_be_bytes(item.payload.len() as u32 + 1);
+impl PeerMessage {
+    /// The message type, `None` for keep-alives which have none.
+    pub fn tag(&self) -> Option<MessageTag> {
+        Some(match self {
+            PeerMessage::KeepAlive => return None,
+            PeerMessage::Choke => MessageTag::Choke,
+            PeerMessage::Unchoke => MessageTag::Unchoke,
+            PeerMessage::Interested => MessageTag::Interested,
+            PeerMessage::NotInterested => MessageTag::NotInterested,
+            PeerMessage::Have(_) => MessageTag::Have,
+            PeerMessage::Bitfield(_) => MessageTag::Bitfield,
+            PeerMessage::Request { .. } => MessageTag::Request,
+            PeerMessage::Piece { .. } => MessageTag::Piece,
+            PeerMessage::Cancel { .. } => MessageTag::Cancel,
+            PeerMessage::Port(_) => MessageTag::Port,
+        })
+    }
 
-        // Reserve space in the buffer.
-        dst.reserve(4 /* len */ + 1 /* tag */ + item.payload.len());
+    /// Length of the payload following the tag.
+    fn payload_len(&self) -> usize {
+        match self {
+            PeerMessage::KeepAlive
+            | PeerMessage::Choke
+            | PeerMessage::Unchoke
+            | PeerMessage::Interested
+            | PeerMessage::NotInterested => 0,
+            PeerMessage::Have(_) => 4,
+            PeerMessage::Bitfield(bitfield) => bitfield.as_bytes().len(),
+            PeerMessage::Request { .. } | PeerMessage::Cancel { .. } => 12,
+            PeerMessage::Piece { block, .. } => 8 + block.len(),
+            PeerMessage::Port(_) => 2,
+        }
+    }
 
-        // Write the length and string to the buffer.
-        dst.extend_from_slice(&len_slice);
-        dst.put_u8(item.tag as u8);
-        dst.extend_from_slice(&item.payload);
+    /// Parses the payload of a message of type `tag`, rejecting payloads of the wrong length.
+    ///
+    /// The block of a `piece` message shares `payload`'s memory rather than being copied.
+    fn parse(tag: MessageTag, mut payload: Bytes) -> Result<Self, FrameError> {
+        let valid = match tag {
+            MessageTag::Have => payload.len() == 4,
+            MessageTag::Request | MessageTag::Cancel => payload.len() == 12,
+            MessageTag::Port => payload.len() == 2,
+            MessageTag::Bitfield => true,
+            MessageTag::Piece => payload.len() >= 8,
+            _ => payload.is_empty(),
+        };
+        if !valid {
+            return Err(FrameError::Malformed {
+                tag,
+                length: payload.len(),
+            });
+        }
+
+        Ok(match tag {
+            MessageTag::Choke => PeerMessage::Choke,
+            MessageTag::Unchoke => PeerMessage::Unchoke,
+            MessageTag::Interested => PeerMessage::Interested,
+            MessageTag::NotInterested => PeerMessage::NotInterested,
+            MessageTag::Have => PeerMessage::Have(payload.get_u32()),
+            MessageTag::Bitfield => PeerMessage::Bitfield(Bitfield::from_payload(payload.to_vec())),
+            MessageTag::Request => PeerMessage::Request {
+                index: payload.get_u32(),
+                begin: payload.get_u32(),
+                length: payload.get_u32(),
+            },
+            MessageTag::Piece => PeerMessage::Piece {
+                index: payload.get_u32(),
+                begin: payload.get_u32(),
+                block: payload,
+            },
+            MessageTag::Cancel => PeerMessage::Cancel {
+                index: payload.get_u32(),
+                begin: payload.get_u32(),
+                length: payload.get_u32(),
+            },
+            MessageTag::Port => PeerMessage::Port(payload.get_u16()),
+        })
+    }
+}
+
+/// Splits a connection into messages, rejecting frames longer than the limit it is built with.
+#[derive(Debug, Clone)]
+pub struct MessageFramer {
+    max_frame: usize,
+}
+
+impl MessageFramer {
+    /// A framer for frames of up to `max_frame` bytes, not counting the length prefix.
+    pub fn new(max_frame: usize) -> Self {
+        Self { max_frame }
+    }
+
+    /// A framer fitting the longest message of a torrent of `num_pieces` pieces downloaded in
+    /// blocks of up to `max_block` bytes, which is either its bitfield or a piece.
+    pub fn for_torrent(num_pieces: usize, max_block: usize) -> Self {
+        let bitfield = 1 + num_pieces.div_ceil(8);
+        let piece = 1 + 8 + max_block;
+        // Requests and cancels are the longest fixed-size messages.
+        Self::new(bitfield.max(piece).max(13))
+    }
+
+    pub fn max_frame(&self) -> usize {
+        self.max_frame
+    }
+}
+
+impl Encoder<PeerMessage> for MessageFramer {
+    type Error = FrameError;
+
+    fn encode(&mut self, item: PeerMessage, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let Some(tag) = item.tag() else {
+            dst.put_u32(0);
+            return Ok(());
+        };
+
+        // Don't send a message if it is longer than the other end will accept.
+        let length = 1 + item.payload_len();
+        if length > self.max_frame {
+            return Err(FrameError::TooLarge {
+                length,
+                max: self.max_frame,
+            });
+        }
+
+        dst.reserve(4 /* len */ + length);
+        dst.put_u32(length as u32);
+        dst.put_u8(tag as u8);
+        match item {
+            PeerMessage::Have(index) => dst.put_u32(index),
+            PeerMessage::Bitfield(bitfield) => dst.extend_from_slice(bitfield.as_bytes()),
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            }
+            | PeerMessage::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                dst.put_u32(index);
+                dst.put_u32(begin);
+                dst.put_u32(length);
+            }
+            PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                dst.put_u32(index);
+                dst.put_u32(begin);
+                dst.extend_from_slice(&block);
+            }
+            PeerMessage::Port(port) => dst.put_u16(port),
+            _ => {}
+        }
 
         Ok(())
     }
 }
 
 impl Decoder for MessageFramer {
-    type Item = Message;
-    type Error = std::io::Error;
+    type Item = PeerMessage;
+    type Error = FrameError;
 
-    fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         if src.len() < 4 {
             // Not enough data to read length marker.
             return Ok(None);
@@ -118,22 +514,17 @@ impl Decoder for MessageFramer {
         length_bytes.copy_from_slice(&src[..4]);
         let length = u32::from_be_bytes(length_bytes) as usize;
 
-        // If this is a hearbeat message then we discard it.
         if length == 0 {
             src.advance(4);
-            return self.decode(src);
+            return Ok(Some(PeerMessage::KeepAlive));
         }
 
-        if src.len() < 5 {
-            // Not enough data to read tag marker.
-            return Ok(None);
-        }
-
-        if length > MAX {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Frame of length {} is too large.", length),
-            ));
+        // Checked before waiting for the rest, so a peer can't make us buffer it.
+        if length > self.max_frame {
+            return Err(FrameError::TooLarge {
+                length,
+                max: self.max_frame,
+            });
         }
 
         if src.len() < 4 + length {
@@ -146,55 +537,372 @@ impl Decoder for MessageFramer {
         }
 
         let tag = src[4].try_into()?;
-        let data = if src.len() > 5 {
-            src[5..4 + length - 1].to_vec()
-        } else {
-            Vec::new()
-        };
-        src.advance(4 + length);
+        let mut payload = src.split_to(4 + length).freeze();
+        payload.advance(5);
+        let message = PeerMessage::parse(tag, payload)?;
 
-        Ok(Some(Message { tag, payload: data }))
+        Ok(Some(message))
     }
 }
 
-pub struct Request {
-    pub index: [u8; 4],
-    pub begin: [u8; 4],
-    pub length: [u8; 4],
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::Rng;
+
+    const INFO_HASH: [u8; 20] = [7; 20];
+
+    #[test]
+    fn validate_bitfields() {
+        let bitfield = Bitfield::from_bytes(&[0b1000_0001, 0b0100_0000], 10).unwrap();
+        assert_eq!(vec![0, 7, 9], bitfield.iter().collect::<Vec<_>>());
+        assert_eq!(3, bitfield.count());
+        assert!(!bitfield.has(10));
+        assert_eq!(
+            Err(BitfieldError::WrongLength {
+                expected: 2,
+                actual: 3
+            }),
+            Bitfield::from_bytes(&[0, 0, 0], 10)
+        );
+        assert_eq!(
+            Err(BitfieldError::SpareBits),
+            Bitfield::from_bytes(&[0, 0b0010_0000], 10)
+        );
+        assert!(Bitfield::from_bytes(&[0xff, 0xff], 16).is_ok());
+        assert!(Bitfield::from_bytes(&[], 0).is_ok());
+    }
 
-impl Request {
-    pub fn new(index: u32, begin: u32, length: u32) -> Self {
-        Self {
-            index: index.to_be_bytes(),
-            begin: begin.to_be_bytes(),
-            length: length.to_be_bytes(),
+    #[test]
+    fn combine_bitfields() {
+        let mut ours = Bitfield::new(10);
+        ours.set(1);
+        ours.set(9);
+        let mut theirs = Bitfield::new(10);
+        for index in [1, 2, 8] {
+            theirs.set(index);
         }
+        let needed = theirs.difference(&ours);
+        assert_eq!(vec![2, 8], needed.iter().collect::<Vec<_>>());
+        ours.union_with(&theirs);
+        assert_eq!(vec![1, 2, 8, 9], ours.iter().collect::<Vec<_>>());
+        assert_eq!(0, theirs.difference(&ours).count());
     }
 
-    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
-        let bytes = self as *mut Self as *mut [u8; std::mem::size_of::<Self>()];
-        let bytes: &mut [u8; std::mem::size_of::<Self>()] = unsafe { &mut *bytes };
-        bytes
+    #[test]
+    fn handshake_wire_format() {
+        let mut handshake = Handshake::new(INFO_HASH, PeerId(*b"-BT0100-abcdefghijkl"));
+        handshake.capabilities.set_extension_protocol(true);
+        let bytes = handshake.to_bytes();
+        assert_eq!(b"\x13BitTorrent protocol", &bytes[..20]);
+        assert_eq!([0, 0, 0, 0, 0, 0x10, 0, 0], bytes[20..28]);
+        assert_eq!(INFO_HASH, bytes[28..48]);
+        assert_eq!(b"-BT0100-abcdefghijkl", &bytes[48..]);
+        assert_eq!(Ok(handshake), Handshake::from_bytes(&bytes));
     }
-}
 
-pub struct Piece {
-    index: [u8; 4],
-    begin: [u8; 4],
-    block: [u8],
-}
+    #[test]
+    fn reject_other_protocols() {
+        let mut bytes = Handshake::new(INFO_HASH, PeerId([1; 20])).to_bytes();
+        bytes[1] = b'b';
+        assert_eq!(
+            Err(HandshakeError::InvalidProtocol),
+            Handshake::from_bytes(&bytes)
+        );
+        let mut bytes = Handshake::new(INFO_HASH, PeerId([1; 20])).to_bytes();
+        bytes[0] = 20;
+        assert_eq!(
+            Err(HandshakeError::InvalidProtocol),
+            Handshake::from_bytes(&bytes)
+        );
+    }
+
+    #[test]
+    fn read_capabilities() {
+        // As sent by libtorrent with DHT enabled.
+        let capabilities = Capabilities([0, 0, 0, 0, 0, 0x10, 0, 0x05]);
+        assert!(capabilities.extension_protocol());
+        assert!(capabilities.fast());
+        assert!(capabilities.dht());
+        assert!(!capabilities.v2());
+        assert!(!Capabilities::default().extension_protocol());
+
+        let mut capabilities = Capabilities::default();
+        capabilities.set_v2(true);
+        capabilities.set_dht(true);
+        capabilities.set_dht(false);
+        assert_eq!(Capabilities([0, 0, 0, 0, 0, 0, 0, 0x10]), capabilities);
+    }
+
+    #[tokio::test]
+    async fn detect_info_hash_mismatch() {
+        let (mut ours, mut theirs) = tokio::io::duplex(HANDSHAKE_LEN);
+        tokio::spawn(async move {
+            Handshake::read(&mut theirs).await.unwrap();
+            let reply = Handshake::new([8; 20], PeerId([2; 20]));
+            reply.write(&mut theirs).await.unwrap();
+        });
+        let result = Handshake::new(INFO_HASH, PeerId([1; 20]))
+            .exchange(&mut ours)
+            .await;
+        assert!(matches!(
+            result,
+            Err(BittorrentError::HandshakeError(
+                HandshakeError::InfoHashMismatch
+            ))
+        ));
+    }
+
+    fn framer() -> MessageFramer {
+        MessageFramer::for_torrent(100, 1 << 14)
+    }
+
+    fn encode(message: PeerMessage) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        framer().encode(message, &mut buf).unwrap();
+        buf.to_vec()
+    }
+
+    #[test]
+    fn round_trip_messages() {
+        let messages = [
+            PeerMessage::KeepAlive,
+            PeerMessage::Choke,
+            PeerMessage::Unchoke,
+            PeerMessage::Interested,
+            PeerMessage::NotInterested,
+            PeerMessage::Have(7),
+            PeerMessage::Bitfield(Bitfield::from_payload(vec![0b1010_0000, 0b1000_0000])),
+            PeerMessage::Request {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            },
+            PeerMessage::Piece {
+                index: 1,
+                begin: 16384,
+                block: Bytes::from_static(b"block"),
+            },
+            PeerMessage::Cancel {
+                index: 1,
+                begin: 0,
+                length: 100,
+            },
+            PeerMessage::Port(6881),
+        ];
+        let mut buf = BytesMut::new();
+        for message in messages.iter().cloned() {
+            buf.extend(encode(message));
+        }
+        let mut framer = framer();
+        for message in messages {
+            assert_eq!(Some(message), framer.decode(&mut buf).unwrap());
+        }
+        assert!(buf.is_empty());
+    }
 
-impl Piece {
-    pub fn index(&self) -> u32 {
-        u32::from_be_bytes(self.index)
+    #[test]
+    fn encode_wire_format() {
+        assert_eq!(vec![0, 0, 0, 0], encode(PeerMessage::KeepAlive));
+        assert_eq!(vec![0, 0, 0, 1, 2], encode(PeerMessage::Interested));
+        assert_eq!(
+            vec![0, 0, 0, 5, 4, 0, 0, 1, 2],
+            encode(PeerMessage::Have(258))
+        );
+        assert_eq!(
+            vec![0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 64, 0],
+            encode(PeerMessage::Request {
+                index: 1,
+                begin: 2,
+                length: 16384,
+            })
+        );
+        assert_eq!(
+            vec![0, 0, 0, 11, 7, 0, 0, 0, 1, 0, 0, 0, 0, 0xab, 0xcd],
+            encode(PeerMessage::Piece {
+                index: 1,
+                begin: 0,
+                block: Bytes::from_static(&[0xab, 0xcd]),
+            })
+        );
     }
 
-    pub fn begin(&self) -> u32 {
-        u32::from_be_bytes(self.begin)
+    #[test]
+    fn wait_for_whole_frames() {
+        let frame = encode(PeerMessage::Have(3));
+        let mut framer = framer();
+        let mut buf = BytesMut::new();
+        for &byte in &frame[..frame.len() - 1] {
+            buf.extend_from_slice(&[byte]);
+            assert_eq!(None, framer.decode(&mut buf).unwrap());
+        }
+        buf.extend_from_slice(&frame[frame.len() - 1..]);
+        assert_eq!(Some(PeerMessage::Have(3)), framer.decode(&mut buf).unwrap());
+    }
+
+    #[test]
+    fn reject_malformed_messages() {
+        let mut framer = framer();
+        for frame in [
+            // Choke with a payload.
+            &[0, 0, 0, 2, 0, 0][..],
+            // Have with a short index.
+            &[0, 0, 0, 4, 4, 0, 0, 1],
+            // Request missing its length.
+            &[0, 0, 0, 9, 6, 0, 0, 0, 1, 0, 0, 0, 2],
+            // Piece without a full header.
+            &[0, 0, 0, 5, 7, 0, 0, 0, 1],
+            // Port with an extra byte.
+            &[0, 0, 0, 4, 9, 0x1a, 0xe1, 0],
+        ] {
+            let mut buf = BytesMut::from(frame);
+            let error = framer.decode(&mut buf).unwrap_err();
+            assert!(matches!(error, FrameError::Malformed { .. }), "{frame:?}");
+        }
+        let mut buf = BytesMut::from(&[0, 0, 0, 1, 20][..]);
+        assert!(matches!(
+            framer.decode(&mut buf),
+            Err(FrameError::UnknownMessage(20))
+        ));
+    }
+
+    #[test]
+    fn limit_frame_length() {
+        // Pieces of 16 KiB blocks are the longest messages of small torrents.
+        let mut framer = MessageFramer::for_torrent(100, 1 << 14);
+        assert_eq!(9 + (1 << 14), framer.max_frame());
+        // The limit applies before the frame has arrived.
+        let mut buf = BytesMut::from(&[0, 0, 0x40, 0x0a, 7][..]);
+        assert!(matches!(
+            framer.decode(&mut buf),
+            Err(FrameError::TooLarge {
+                length: 16394,
+                max: 16393
+            })
+        ));
+
+        // Torrents with a million pieces have bitfields of 125 KB.
+        let mut framer = MessageFramer::for_torrent(1_000_000, 1 << 14);
+        let bitfield = PeerMessage::Bitfield(Bitfield::new(1_000_000));
+        let mut buf = BytesMut::new();
+        framer.encode(bitfield.clone(), &mut buf).unwrap();
+        assert_eq!(Some(bitfield), framer.decode(&mut buf).unwrap());
+
+        let mut framer = MessageFramer::new(8);
+        let mut buf = BytesMut::new();
+        assert!(matches!(
+            framer.encode(PeerMessage::Have(1), &mut buf),
+            Ok(())
+        ));
+        assert!(matches!(
+            framer.encode(
+                PeerMessage::Request {
+                    index: 0,
+                    begin: 0,
+                    length: 1
+                },
+                &mut buf
+            ),
+            Err(FrameError::TooLarge { length: 13, max: 8 })
+        ));
+    }
+
+    fn random_bytes(rng: &mut Rng, max_len: usize) -> Vec<u8> {
+        (0..rng.below(max_len + 1))
+            .map(|_| rng.next_u32() as u8)
+            .collect()
+    }
+
+    fn random_message(rng: &mut Rng) -> PeerMessage {
+        match rng.below(11) {
+            0 => PeerMessage::KeepAlive,
+            1 => PeerMessage::Choke,
+            2 => PeerMessage::Unchoke,
+            3 => PeerMessage::Interested,
+            4 => PeerMessage::NotInterested,
+            5 => PeerMessage::Have(rng.next_u32()),
+            6 => PeerMessage::Bitfield(Bitfield::from_payload(random_bytes(rng, 20))),
+            7 => PeerMessage::Request {
+                index: rng.next_u32(),
+                begin: rng.next_u32(),
+                length: rng.next_u32(),
+            },
+            8 => PeerMessage::Piece {
+                index: rng.next_u32(),
+                begin: rng.next_u32(),
+                block: Bytes::from(random_bytes(rng, 100)),
+            },
+            9 => PeerMessage::Cancel {
+                index: rng.next_u32(),
+                begin: rng.next_u32(),
+                length: rng.next_u32(),
+            },
+            _ => PeerMessage::Port(rng.next_u32() as u16),
+        }
+    }
+
+    /// Feeds `input` to `framer` in chunks of random size, as reads from a socket would arrive.
+    fn decode_in_chunks(
+        rng: &mut Rng,
+        framer: &mut MessageFramer,
+        input: &[u8],
+    ) -> Result<Vec<PeerMessage>, FrameError> {
+        let mut messages = Vec::new();
+        let mut buf = BytesMut::new();
+        let mut rest = input;
+        while !rest.is_empty() {
+            let (chunk, remaining) = rest.split_at(1 + rng.below(rest.len()));
+            rest = remaining;
+            buf.extend_from_slice(chunk);
+            while let Some(message) = framer.decode(&mut buf)? {
+                messages.push(message);
+            }
+        }
+        Ok(messages)
     }
 
-    pub fn block(&self) -> &[u8] {
-        &self.block
+    #[test]
+    fn fuzz_split_message_streams() {
+        let mut rng = Rng::with_seed(25);
+        for _ in 0..2000 {
+            let messages: Vec<_> = (0..rng.below(10))
+                .map(|_| random_message(&mut rng))
+                .collect();
+            let mut wire = Vec::new();
+            for message in &messages {
+                wire.extend(encode(message.clone()));
+            }
+            let decoded = decode_in_chunks(&mut rng, &mut framer(), &wire).unwrap();
+            assert_eq!(messages, decoded);
+        }
+    }
+
+    #[test]
+    fn fuzz_corrupt_input() {
+        let mut rng = Rng::with_seed(25);
+        let mut framer = MessageFramer::new(64);
+        for _ in 0..20_000 {
+            let input = match rng.below(3) {
+                // Arbitrary bytes, nearly always too long a frame.
+                0 => random_bytes(&mut rng, 40),
+                // A plausible length and tag followed by arbitrary bytes.
+                1 => {
+                    let mut input = vec![0, 0, 0, rng.below(20) as u8, rng.below(12) as u8];
+                    input.extend(random_bytes(&mut rng, 30));
+                    input
+                }
+                // A valid message with a byte changed.
+                _ => {
+                    let mut input = encode(random_message(&mut rng));
+                    let at = rng.below(input.len());
+                    input[at] = rng.next_u32() as u8;
+                    input
+                }
+            };
+            // Decoding must never panic, and only fail on the peer's account.
+            if let Err(error) = decode_in_chunks(&mut rng, &mut framer, &input) {
+                assert!(!matches!(error, FrameError::Io(_)), "{input:?}: {error:?}");
+            }
+        }
     }
 }
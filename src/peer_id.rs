@@ -0,0 +1,176 @@
+use std::fmt;
+
+use crate::random::Rng;
+
+/// Azureus-style prefix identifying this client and its version.
+const CLIENT_PREFIX: &[u8; 8] = b"-BT0100-";
+
+/// Azureus-style client codes, from the `-XX1234-` prefix.
+const AZUREUS_CLIENTS: &[(&[u8; 2], &str)] = &[
+    (b"AZ", "Vuze"),
+    (b"BC", "BitComet"),
+    (b"BI", "BiglyBT"),
+    (b"BT", "BitTorrent"),
+    (b"DE", "Deluge"),
+    (b"FD", "Free Download Manager"),
+    (b"KT", "KTorrent"),
+    (b"LT", "libtorrent"),
+    (b"lt", "libtorrent (Rasterbar)"),
+    (b"PI", "PicoTorrent"),
+    (b"qB", "qBittorrent"),
+    (b"RT", "rTorrent"),
+    (b"SD", "Thunder"),
+    (b"TL", "Tribler"),
+    (b"TR", "Transmission"),
+    (b"UT", "µTorrent"),
+    (b"UM", "µTorrent for Mac"),
+    (b"WW", "WebTorrent"),
+];
+
+/// The 20 byte identifier a client presents to trackers and peers.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PeerId(pub [u8; 20]);
+
+impl PeerId {
+    /// Generates an ID for this session: `-BT0100-` followed by 12 random alphanumerics.
+    pub fn generate() -> Self {
+        const ALPHABET: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+        let mut rng = Rng::new();
+        let mut id = [0u8; 20];
+        id[..8].copy_from_slice(CLIENT_PREFIX);
+        for byte in &mut id[8..] {
+            *byte = ALPHABET[rng.below(ALPHABET.len())];
+        }
+        Self(id)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+
+    /// Name and version of the client that generated this ID, if it follows a known convention.
+    pub fn client(&self) -> Option<String> {
+        let id = &self.0;
+        // Azureus style, `-XX1234-`.
+        if id[0] == b'-' && id[7] == b'-' {
+            let code: &[u8; 2] = id[1..3].try_into().expect("length 2");
+            let (_, name) = AZUREUS_CLIENTS.iter().find(|(c, _)| *c == code)?;
+            let version = &id[3..7];
+            if !version.iter().all(u8::is_ascii_alphanumeric) {
+                return Some(name.to_string());
+            }
+            return Some(format!("{name} {}", azureus_version(version)));
+        }
+        // Mainline style, `M1-2-3--`.
+        if id[0] == b'M' {
+            let rest = std::str::from_utf8(&id[1..8]).ok()?;
+            let parts: Vec<&str> = rest.trim_end_matches('-').split('-').collect();
+            if parts.len() == 3 && parts.iter().all(|p| p.parse::<u8>().is_ok()) {
+                return Some(format!("BitTorrent {}", parts.join(".")));
+            }
+        }
+        None
+    }
+}
+
+/// Turns `4630` into `4.6.3`, digits beyond 9 are written as letters in some clients.
+fn azureus_version(version: &[u8]) -> String {
+    let mut parts: Vec<String> = version
+        .iter()
+        .map(|&c| match c {
+            b'0'..=b'9' => (c - b'0').to_string(),
+            b'A'..=b'Z' => (c - b'A' + 10).to_string(),
+            b'a'..=b'z' => (c - b'a' + 36).to_string(),
+            _ => unreachable!("checked to be alphanumeric"),
+        })
+        .collect();
+    while parts.len() > 2 && parts.last().is_some_and(|p| p == "0") {
+        parts.pop();
+    }
+    parts.join(".")
+}
+
+impl From<[u8; 20]> for PeerId {
+    fn from(id: [u8; 20]) -> Self {
+        Self(id)
+    }
+}
+
+impl fmt::Display for PeerId {
+    /// Printable IDs are shown as text, anything else as hex.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.iter().all(|b| b.is_ascii_graphic()) {
+            f.write_str(std::str::from_utf8(&self.0).expect("ASCII is valid UTF-8"))
+        } else {
+            f.write_str(&hex::encode(self.0))
+        }
+    }
+}
+
+impl fmt::Debug for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PeerId({self})")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(id: &[u8; 20]) -> Option<String> {
+        PeerId(*id).client()
+    }
+
+    #[test]
+    fn generate_unique_ids() {
+        let a = PeerId::generate();
+        let b = PeerId::generate();
+        assert_ne!(a, b);
+        assert!(a.as_bytes().starts_with(b"-BT0100-"));
+        assert!(a.as_bytes()[8..].iter().all(u8::is_ascii_alphanumeric));
+        assert_eq!(Some("BitTorrent 0.1".into()), a.client());
+    }
+
+    #[test]
+    fn recognise_azureus_style_clients() {
+        assert_eq!(
+            Some("qBittorrent 4.6.3".into()),
+            client(b"-qB4630-abcdefghijkl")
+        );
+        assert_eq!(
+            Some("Transmission 2.9.4".into()),
+            client(b"-TR2940-abcdefghijkl")
+        );
+        assert_eq!(
+            Some("libtorrent (Rasterbar) 2.0".into()),
+            client(b"-lt2000-abcdefghijkl")
+        );
+        assert_eq!(
+            Some("Deluge 1.3.15".into()),
+            client(b"-DE13F0-abcdefghijkl")
+        );
+        assert_eq!(None, client(b"-ZZ1000-abcdefghijkl"));
+    }
+
+    #[test]
+    fn recognise_mainline_style_clients() {
+        assert_eq!(
+            Some("BitTorrent 4.4.0".into()),
+            client(b"M4-4-0--abcdefghijkl")
+        );
+        assert_eq!(
+            Some("BitTorrent 7.10.5".into()),
+            client(b"M7-10-5-abcdefghijkl")
+        );
+        assert_eq!(None, client(b"Mxyz----abcdefghijkl"));
+    }
+
+    #[test]
+    fn display_binary_ids_as_hex() {
+        assert_eq!(
+            "00112233445566778899",
+            PeerId(*b"00112233445566778899").to_string()
+        );
+        assert_eq!("00".repeat(20), PeerId([0; 20]).to_string());
+    }
+}
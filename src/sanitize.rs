@@ -0,0 +1,243 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+/// Longest file name most filesystems accept, in bytes.
+const MAX_COMPONENT_LEN: usize = 255;
+
+/// Device names that Windows refuses to create files under, with or without an extension.
+/// Windows counts the superscript digits ¹, ² and ³ as digits in port names too.
+const RESERVED_NAMES: [&str; 32] = [
+    "CON", "PRN", "AUX", "NUL", "CONIN$", "CONOUT$", "COM0", "COM1", "COM2", "COM3", "COM4",
+    "COM5", "COM6", "COM7", "COM8", "COM9", "COM¹", "COM²", "COM³", "LPT0", "LPT1", "LPT2", "LPT3",
+    "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9", "LPT¹", "LPT²", "LPT³",
+];
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum PathError {
+    #[error("path {0:?} has no usable components")]
+    Empty(Vec<String>),
+    #[error("several files map to {0}")]
+    Collision(PathBuf),
+}
+
+/// A modification made while sanitising a path from a torrent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathChange {
+    /// An empty, `.` or `..` component was dropped.
+    Removed(String),
+    /// A component was rewritten to be safe to create on disk.
+    Rewritten { from: String, to: String },
+}
+
+impl fmt::Display for PathChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PathChange::Removed(component) => write!(f, "removed {component:?}"),
+            PathChange::Rewritten { from, to } => write!(f, "renamed {from:?} to {to:?}"),
+        }
+    }
+}
+
+/// A relative path that is guaranteed to stay inside the directory it is joined onto.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SanitizedPath {
+    pub path: PathBuf,
+    pub changes: Vec<PathChange>,
+}
+
+/// Maps the `path` components of a torrent file onto a safe relative path.
+///
+/// Traversal components are dropped, and anything that could escape the output directory or
+/// fails to be created on common filesystems is rewritten. Fails if nothing usable remains.
+pub fn sanitize_path<S: AsRef<str>>(components: &[S]) -> Result<SanitizedPath, PathError> {
+    let mut path = PathBuf::new();
+    let mut changes = Vec::new();
+    for component in components {
+        let component = component.as_ref();
+        match sanitize_component(component) {
+            Some(safe) => {
+                if safe != component {
+                    changes.push(PathChange::Rewritten {
+                        from: component.to_string(),
+                        to: safe.clone(),
+                    });
+                }
+                path.push(safe);
+            }
+            None => changes.push(PathChange::Removed(component.to_string())),
+        }
+    }
+    if path.as_os_str().is_empty() {
+        return Err(PathError::Empty(
+            components.iter().map(|c| c.as_ref().to_string()).collect(),
+        ));
+    }
+    Ok(SanitizedPath { path, changes })
+}
+
+/// Returns a safe single path component, or `None` if the component should be dropped.
+fn sanitize_component(component: &str) -> Option<String> {
+    let mut safe: String = component
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    // Windows silently strips these, which would make `a.` and `a` the same file.
+    let trimmed_len = safe.trim_end_matches(['.', ' ']).len();
+    if trimmed_len == 0 {
+        return None;
+    }
+    safe.truncate(trimmed_len);
+
+    let stem = safe.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+    {
+        safe.insert(0, '_');
+    }
+
+    if safe.len() > MAX_COMPONENT_LEN {
+        let mut end = MAX_COMPONENT_LEN;
+        while !safe.is_char_boundary(end) {
+            end -= 1;
+        }
+        safe.truncate(end);
+    }
+    Some(safe)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Component, Path};
+
+    use super::*;
+
+    fn sanitize(components: &[&str]) -> SanitizedPath {
+        sanitize_path(components).expect("path should be usable")
+    }
+
+    fn assert_contained(path: &Path) {
+        assert!(path.is_relative(), "{path:?} is not relative");
+        assert!(
+            path.components().all(|c| matches!(c, Component::Normal(_))),
+            "{path:?} has non-normal components"
+        );
+    }
+
+    #[test]
+    fn keep_ordinary_paths() {
+        let sanitized = sanitize(&["dir", "sub", "file.txt"]);
+        assert_eq!(PathBuf::from("dir/sub/file.txt"), sanitized.path);
+        assert!(sanitized.changes.is_empty());
+    }
+
+    #[test]
+    fn drop_traversal_components() {
+        let sanitized = sanitize(&["..", "..", "etc", "passwd"]);
+        assert_eq!(PathBuf::from("etc/passwd"), sanitized.path);
+        assert_eq!(
+            vec![
+                PathChange::Removed("..".into()),
+                PathChange::Removed("..".into())
+            ],
+            sanitized.changes
+        );
+        assert_eq!(PathBuf::from("a/b"), sanitize(&["a", ".", "", "b"]).path);
+    }
+
+    #[test]
+    fn rewrite_embedded_separators_and_roots() {
+        let sanitized = sanitize(&["/etc/passwd"]);
+        assert_eq!(PathBuf::from("_etc_passwd"), sanitized.path);
+        assert_eq!(
+            vec![PathChange::Rewritten {
+                from: "/etc/passwd".into(),
+                to: "_etc_passwd".into()
+            }],
+            sanitized.changes
+        );
+        assert_eq!(PathBuf::from(".._x"), sanitize(&["../x"]).path);
+        assert_eq!(PathBuf::from(".._.._x"), sanitize(&["..\\..\\x"]).path);
+        assert_eq!(PathBuf::from("C__Windows"), sanitize(&["C:\\Windows"]).path);
+        assert_eq!(
+            PathBuf::from("__server_share"),
+            sanitize(&["\\\\server\\share"]).path
+        );
+    }
+
+    #[test]
+    fn rewrite_nul_and_control_characters() {
+        assert_eq!(PathBuf::from("a_b"), sanitize(&["a\0b"]).path);
+        assert_eq!(PathBuf::from("a_b_c"), sanitize(&["a\nb\u{7f}c"]).path);
+        assert_eq!(PathBuf::from("what_"), sanitize(&["what?"]).path);
+    }
+
+    #[test]
+    fn rewrite_windows_reserved_names() {
+        assert_eq!(PathBuf::from("_CON"), sanitize(&["CON"]).path);
+        assert_eq!(PathBuf::from("_nul.txt"), sanitize(&["nul.txt"]).path);
+        assert_eq!(
+            PathBuf::from("_Com1.tar.gz"),
+            sanitize(&["Com1.tar.gz"]).path
+        );
+        assert_eq!(PathBuf::from("_com0"), sanitize(&["com0"]).path);
+        assert_eq!(PathBuf::from("_LPT0.bin"), sanitize(&["LPT0.bin"]).path);
+        assert_eq!(PathBuf::from("_conin$"), sanitize(&["conin$"]).path);
+        assert_eq!(
+            PathBuf::from("_CONOUT$.log"),
+            sanitize(&["CONOUT$.log"]).path
+        );
+        assert_eq!(PathBuf::from("_COM¹"), sanitize(&["COM¹"]).path);
+        assert_eq!(PathBuf::from("_lpt³.txt"), sanitize(&["lpt³.txt"]).path);
+        assert_eq!(PathBuf::from("COM⁴"), sanitize(&["COM⁴"]).path);
+        assert_eq!(PathBuf::from("CONSOLE"), sanitize(&["CONSOLE"]).path);
+        assert_eq!(PathBuf::from("file"), sanitize(&["file. . "]).path);
+        assert_eq!(PathBuf::from("x"), sanitize(&["...", "x"]).path);
+    }
+
+    #[test]
+    fn truncate_long_components() {
+        let long = "é".repeat(200);
+        let sanitized = sanitize(&[&long]);
+        let name = sanitized.path.to_str().unwrap();
+        assert!(name.len() <= MAX_COMPONENT_LEN);
+        assert!(long.starts_with(name));
+    }
+
+    #[test]
+    fn reject_paths_without_usable_components() {
+        assert_eq!(
+            Err(PathError::Empty(vec!["..".into(), ".".into()])),
+            sanitize_path(&["..", "."])
+        );
+        assert!(sanitize_path::<&str>(&[]).is_err());
+        assert!(sanitize_path(&[""]).is_err());
+    }
+
+    #[test]
+    fn hostile_paths_stay_contained() {
+        let hostile: &[&[&str]] = &[
+            &["..", "..", "..", "tmp", "evil"],
+            &["/", "etc", "shadow"],
+            &["a", "..", "..", "b"],
+            &["C:", "evil.exe"],
+            &["\\\\?\\C:\\evil"],
+            &["~", ".ssh", "authorized_keys"],
+            &["..\u{0}", "x"],
+            &["AUX.", "LPT9 "],
+            &[". .", "x"],
+        ];
+        for components in hostile {
+            let sanitized = sanitize(components);
+            assert_contained(&sanitized.path);
+            assert!(Path::new("/out").join(&sanitized.path).starts_with("/out"));
+        }
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::download::DownloadError;
+use crate::error::BittorrentError;
+use crate::sanitize::{sanitize_path, PathChange, PathError};
+use crate::torrent::{Info, Keys};
+
+/// Writes downloaded pieces to the files they belong to.
+#[derive(Debug, Clone)]
+pub struct Storage {
+    info: Info,
+    /// Destination of each file of the torrent, in piece-space order.
+    paths: Vec<PathBuf>,
+    /// Changes made to the torrent's paths to keep them inside the output directory.
+    changes: Vec<(PathBuf, Vec<PathChange>)>,
+}
+
+impl Storage {
+    /// Single-file torrents are written to `output` itself, multi-file torrents go into
+    /// `output/<name>/` following each file's sanitised `path`.
+    pub fn new(info: Info, output: &Path) -> Result<Self, BittorrentError> {
+        let mut paths = Vec::new();
+        let mut changes = Vec::new();
+        match &info.keys {
+            Keys::SingleFile { .. } => paths.push(output.to_path_buf()),
+            Keys::MultiFile { files } => {
+                let name = sanitize_path(&[&info.name])?;
+                let root = output.join(&name.path);
+                if !name.changes.is_empty() {
+                    changes.push((root.clone(), name.changes));
+                }
+                let mut seen = HashSet::new();
+                for file in files {
+                    let sanitized = sanitize_path(&file.path)?;
+                    let path = root.join(&sanitized.path);
+                    // Case-insensitive filesystems would merge files differing only in case.
+                    if !seen.insert(path.to_string_lossy().to_lowercase()) {
+                        return Err(PathError::Collision(path).into());
+                    }
+                    if !sanitized.changes.is_empty() {
+                        changes.push((path.clone(), sanitized.changes));
+                    }
+                    paths.push(path);
+                }
+            }
+        }
+        Ok(Self {
+            info,
+            paths,
+            changes,
+        })
+    }
+
+    /// Files whose paths had to be altered, along with what was changed.
+    pub fn changes(&self) -> &[(PathBuf, Vec<PathChange>)] {
+        &self.changes
+    }
+
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    /// Creates every file, along with its parent directories, at its final length.
+    pub fn allocate(&self) -> Result<(), BittorrentError> {
+        for (path, length) in self.paths.iter().zip(self.info.file_lengths()) {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(path)?;
+            file.set_len(length as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the piece at `index`, splitting it across file boundaries as needed.
+    ///
+    /// `data` must be exactly as long as the piece.
+    pub fn write_piece(&self, index: usize, data: &[u8]) -> Result<(), BittorrentError> {
+        if index >= self.info.pieces.0.len() {
+            return Err(DownloadError::NoSuchPiece(index).into());
+        }
+        let expected = self.info.piece_len(index);
+        if data.len() != expected {
+            return Err(BittorrentError::PieceLength {
+                index,
+                expected,
+                actual: data.len(),
+            });
+        }
+        let mut data = data;
+        for slice in self.info.piece_file_slices(index) {
+            let (chunk, rest) = data.split_at(slice.len);
+            let mut file = OpenOptions::new()
+                .write(true)
+                .open(&self.paths[slice.file])?;
+            file.seek(SeekFrom::Start(slice.offset as u64))?;
+            file.write_all(chunk)?;
+            data = rest;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::{File, Hashes};
+
+    fn info(keys: Keys, piece_length: usize) -> Info {
+        Info {
+            name: "dir".into(),
+            piece_length,
+            pieces: Hashes(vec![[0; 20]; 3]),
+            keys,
+        }
+    }
+
+    #[test]
+    fn write_multi_file_pieces() {
+        let files = vec![
+            File {
+                length: 5,
+                path: vec!["a.txt".into()],
+            },
+            File {
+                length: 0,
+                path: vec!["empty".into()],
+            },
+            File {
+                length: 6,
+                path: vec!["sub".into(), "b.txt".into()],
+            },
+        ];
+        let info = info(Keys::MultiFile { files }, 4);
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(info, dir.path()).unwrap();
+        storage.allocate().unwrap();
+
+        let data = b"hello world";
+        for (index, piece) in data.chunks(4).enumerate() {
+            storage.write_piece(index, piece).unwrap();
+        }
+
+        let root = dir.path().join("dir");
+        assert_eq!(b"hello", &fs::read(root.join("a.txt")).unwrap()[..]);
+        assert!(fs::read(root.join("empty")).unwrap().is_empty());
+        assert_eq!(b" world", &fs::read(root.join("sub/b.txt")).unwrap()[..]);
+    }
+
+    #[test]
+    fn write_single_file_pieces_out_of_order() {
+        let info = info(Keys::SingleFile { length: 10 }, 4);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out.bin");
+        let storage = Storage::new(info, &output).unwrap();
+        storage.allocate().unwrap();
+
+        storage.write_piece(2, b"89").unwrap();
+        storage.write_piece(0, b"0123").unwrap();
+        storage.write_piece(1, b"4567").unwrap();
+
+        assert_eq!(b"0123456789", &fs::read(output).unwrap()[..]);
+    }
+
+    #[test]
+    fn reject_pieces_of_the_wrong_length() {
+        let info = info(Keys::SingleFile { length: 10 }, 4);
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(info, &dir.path().join("out.bin")).unwrap();
+        storage.allocate().unwrap();
+
+        assert!(matches!(
+            storage.write_piece(0, b"01"),
+            Err(BittorrentError::PieceLength {
+                index: 0,
+                expected: 4,
+                actual: 2
+            })
+        ));
+        assert!(matches!(
+            storage.write_piece(2, b"8901"),
+            Err(BittorrentError::PieceLength { expected: 2, .. })
+        ));
+        assert!(matches!(
+            storage.write_piece(3, b""),
+            Err(BittorrentError::DownloadError(DownloadError::NoSuchPiece(
+                3
+            )))
+        ));
+    }
+
+    fn file(path: &[&str]) -> File {
+        File {
+            length: 1,
+            path: path.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn keep_hostile_paths_inside_output() {
+        let files = vec![file(&["..", "..", "escape"]), file(&["/abs", "x"])];
+        let mut info = info(Keys::MultiFile { files }, 4);
+        info.name = "../../name".into();
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::new(info, dir.path()).unwrap();
+        storage.allocate().unwrap();
+
+        let root = dir.path().join(".._.._name");
+        assert_eq!(&[root.join("escape"), root.join("_abs/x")], storage.paths());
+        assert_eq!(3, storage.changes().len());
+        assert!(storage.paths().iter().all(|p| p.exists()));
+    }
+
+    #[test]
+    fn reject_colliding_paths() {
+        let files = vec![file(&["a", "b"]), file(&["..", "a", "B"])];
+        let info = info(Keys::MultiFile { files }, 4);
+        assert!(matches!(
+            Storage::new(info, Path::new("/out")),
+            Err(BittorrentError::PathError(PathError::Collision(_)))
+        ));
+    }
+
+    #[test]
+    fn reject_unusable_paths() {
+        let files = vec![file(&["..", "."])];
+        let info = info(Keys::MultiFile { files }, 4);
+        assert!(matches!(
+            Storage::new(info, Path::new("/out")),
+            Err(BittorrentError::PathError(PathError::Empty(_)))
+        ));
+    }
+}
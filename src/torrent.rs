@@ -3,25 +3,82 @@ use std::path::PathBuf;
 use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
 
+use crate::bencode::{self, BencodeValue};
 use crate::error::BittorrentError;
 
+pub use self::hashes::Hashes;
+
 /// Metainfo files (also known as .torrent files)
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone)]
 pub struct Torrent {
     /// The URL of the tracker.
-    pub announce: String,
+    pub announce: Option<String>,
+    /// Tiers of backup trackers (BEP 12), takes precedence over `announce` when present.
+    pub announce_list: Option<Vec<Vec<String>>>,
     /// Torrent
     pub info: Info,
+    /// The info dictionary exactly as it appeared in the metainfo file.
+    info_bytes: Vec<u8>,
 }
 
 impl Torrent {
     pub fn from_file(path: PathBuf) -> Result<Self, BittorrentError> {
         let torrent_bytes = std::fs::read(path)?;
-        serde_bencode::from_bytes(&torrent_bytes).map_err(BittorrentError::BencodeError)
+        Self::from_bytes(&torrent_bytes)
+    }
+
+    /// Parses a metainfo file, which may come from anywhere and is checked field by field.
+    ///
+    /// Dictionary keys are accepted in any order, as many authoring tools don't sort them.
+    pub fn from_bytes(torrent_bytes: &[u8]) -> Result<Self, BittorrentError> {
+        // Hashing the original bytes rather than re-encoding `Info` keeps keys we don't model.
+        let span = bencode::dict_value_span(torrent_bytes, b"info")?
+            .ok_or(BittorrentError::MissingInfo)?;
+        let torrent = bencode::decode_lenient(torrent_bytes)?;
+        let info = torrent.get(b"info").ok_or(BittorrentError::MissingInfo)?;
+        let announce_list = match torrent.get(b"announce-list") {
+            Some(tiers) => Some(
+                list(tiers, |tier| list(tier, string))
+                    .ok_or(malformed("announce-list is not a list of lists of URLs"))?,
+            ),
+            None => None,
+        };
+        Ok(Self {
+            announce: match torrent.get(b"announce") {
+                Some(url) => Some(string(url).ok_or(malformed("announce is not a URL"))?),
+                None => None,
+            },
+            announce_list,
+            info: Info::from_bencode(info)?,
+            info_bytes: torrent_bytes[span].to_vec(),
+        })
+    }
+
+    /// Tracker tiers in the order they should be tried.
+    ///
+    /// Per BEP 12, `announce` is only used when there is no non-empty `announce-list`.
+    pub fn tracker_tiers(&self) -> Vec<Vec<String>> {
+        let tiers: Vec<Vec<String>> = self
+            .announce_list
+            .iter()
+            .flatten()
+            .filter(|tier| !tier.is_empty())
+            .cloned()
+            .collect();
+        if !tiers.is_empty() {
+            return tiers;
+        }
+        self.announce.iter().map(|url| vec![url.clone()]).collect()
+    }
+
+    /// Raw bencoded info dictionary.
+    pub fn info_bytes(&self) -> &[u8] {
+        &self.info_bytes
     }
 
+    /// SHA1 of the raw info dictionary.
     pub fn info_hash(&self) -> [u8; 20] {
-        self.info.hash()
+        Sha1::digest(&self.info_bytes).into()
     }
 }
 
@@ -36,17 +93,114 @@ pub struct Info {
     #[serde(rename = "piece length")]
     pub piece_length: usize,
     /// Each entry of pieces is the SHA1 hash of the corresponding index.
-    pub pieces: hashes::Hashes,
+    pub pieces: Hashes,
     /// Download represents a single file or a set of files.
     #[serde(flatten)]
     pub keys: Keys,
 }
 
 impl Info {
-    pub fn hash(&self) -> [u8; 20] {
-        let encoded = serde_bencode::to_bytes(&self).expect("bencode should be fine");
-        Sha1::digest(encoded).into()
+    /// Reads an info dictionary, rejecting missing fields and fields of the wrong type.
+    pub fn from_bencode(info: &BencodeValue) -> Result<Self, BittorrentError> {
+        let name = info
+            .get(b"name")
+            .and_then(string)
+            .ok_or(malformed("name is not a UTF-8 string"))?;
+        let piece_length = info
+            .get(b"piece length")
+            .and_then(count)
+            .filter(|&length| length > 0)
+            .ok_or(malformed("piece length is not a positive integer"))?;
+        let pieces = info
+            .get(b"pieces")
+            .and_then(BencodeValue::as_bytes)
+            .filter(|pieces| pieces.len().is_multiple_of(20))
+            .ok_or(malformed("pieces is not a string of 20-byte hashes"))?;
+        let pieces = pieces
+            .chunks_exact(20)
+            .map(|hash| hash.try_into().expect("guaranteed to be length 20"))
+            .collect();
+        // As with `Keys`, a `length` makes it a single-file torrent.
+        let keys = match (info.get(b"length"), info.get(b"files")) {
+            (Some(length), _) => Keys::SingleFile {
+                length: count(length).ok_or(malformed("length is not a non-negative integer"))?,
+            },
+            (None, Some(files)) => Keys::MultiFile {
+                files: list(files, File::from_bencode)
+                    .ok_or(malformed("files is not a list of files"))?,
+            },
+            (None, None) => return Err(malformed("info has neither length nor files")),
+        };
+        Ok(Self {
+            name,
+            piece_length,
+            pieces: Hashes(pieces),
+            keys,
+        })
+    }
+
+    /// Total number of bytes across all files.
+    pub fn total_length(&self) -> usize {
+        match &self.keys {
+            Keys::SingleFile { length } => *length,
+            Keys::MultiFile { files } => files.iter().map(|f| f.length).sum(),
+        }
+    }
+
+    /// Length of every file, in the order they are laid out in the piece space.
+    pub fn file_lengths(&self) -> Vec<usize> {
+        match &self.keys {
+            Keys::SingleFile { length } => vec![*length],
+            Keys::MultiFile { files } => files.iter().map(|f| f.length).collect(),
+        }
+    }
+
+    /// Length of the piece at `index`, the last piece may be shorter than `piece_length`.
+    pub fn piece_len(&self, index: usize) -> usize {
+        let start = index * self.piece_length;
+        self.piece_length
+            .min(self.total_length().saturating_sub(start))
     }
+
+    /// Maps `len` bytes starting at `offset` in the concatenated piece space onto the files
+    /// that hold them.
+    pub fn file_slices(&self, offset: usize, len: usize) -> Vec<FileSlice> {
+        let mut slices = Vec::new();
+        let end = offset + len;
+        let mut file_start = 0;
+        for (file, file_len) in self.file_lengths().into_iter().enumerate() {
+            let file_end = file_start + file_len;
+            let start = offset.max(file_start);
+            let stop = end.min(file_end);
+            if start < stop {
+                slices.push(FileSlice {
+                    file,
+                    offset: start - file_start,
+                    len: stop - start,
+                });
+            }
+            if file_end >= end {
+                break;
+            }
+            file_start = file_end;
+        }
+        slices
+    }
+
+    /// Maps the piece at `index` onto the files that hold it.
+    pub fn piece_file_slices(&self, index: usize) -> Vec<FileSlice> {
+        self.file_slices(index * self.piece_length, self.piece_len(index))
+    }
+}
+
+/// A contiguous byte range within one file of the torrent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSlice {
+    /// Index of the file, zero for single-file torrents.
+    pub file: usize,
+    /// Offset within the file.
+    pub offset: usize,
+    pub len: usize,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -71,6 +225,32 @@ pub struct File {
     pub path: Vec<String>,
 }
 
+impl File {
+    fn from_bencode(file: &BencodeValue) -> Option<Self> {
+        Some(Self {
+            length: file.get(b"length").and_then(count)?,
+            path: list(file.get(b"path")?, string)?,
+        })
+    }
+}
+
+fn malformed(reason: &'static str) -> BittorrentError {
+    BittorrentError::MalformedTorrent(reason)
+}
+
+fn string(value: &BencodeValue) -> Option<String> {
+    value.as_str().map(String::from)
+}
+
+fn count(value: &BencodeValue) -> Option<usize> {
+    value.as_int().and_then(|n| usize::try_from(n).ok())
+}
+
+/// Converts every element of a list, failing if it isn't a list or any element doesn't fit.
+fn list<T>(value: &BencodeValue, item: impl Fn(&BencodeValue) -> Option<T>) -> Option<Vec<T>> {
+    value.as_list()?.iter().map(item).collect()
+}
+
 mod hashes {
     use serde::de::{self, Visitor};
     use serde::{Deserialize, Serialize, Serializer};
@@ -90,7 +270,7 @@ mod hashes {
         where
             E: de::Error,
         {
-            if v.len() % 20 != 0 {
+            if !v.len().is_multiple_of(20) {
                 return Err(E::custom(format!("length is {}", v.len())));
             }
             let chunks = v
@@ -121,3 +301,269 @@ mod hashes {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::bencode::BencodeValue;
+
+    fn dict(entries: Vec<(&str, BencodeValue)>) -> BencodeValue {
+        BencodeValue::Dict(
+            entries
+                .into_iter()
+                .map(|(k, v)| (k.as_bytes().to_vec(), v))
+                .collect::<BTreeMap<_, _>>(),
+        )
+    }
+
+    fn info_with(extra: Vec<(&str, BencodeValue)>) -> BencodeValue {
+        let mut entries = vec![
+            ("length", BencodeValue::Int(3)),
+            ("name", "a.txt".into()),
+            ("piece length", BencodeValue::Int(16384)),
+            ("pieces", BencodeValue::Bytes(vec![7; 20])),
+        ];
+        entries.extend(extra);
+        dict(entries)
+    }
+
+    fn torrent_with(info: &BencodeValue) -> Vec<u8> {
+        dict(vec![
+            ("announce", "http://tracker.example/announce".into()),
+            ("info", info.clone()),
+        ])
+        .encode()
+    }
+
+    fn multi_file_info(lengths: &[usize], piece_length: usize) -> Info {
+        let total: usize = lengths.iter().sum();
+        Info {
+            name: "dir".into(),
+            piece_length,
+            pieces: Hashes(vec![[0; 20]; total.div_ceil(piece_length)]),
+            keys: Keys::MultiFile {
+                files: lengths
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &length)| File {
+                        length,
+                        path: vec![format!("{i}.bin")],
+                    })
+                    .collect(),
+            },
+        }
+    }
+
+    fn slice(file: usize, offset: usize, len: usize) -> FileSlice {
+        FileSlice { file, offset, len }
+    }
+
+    #[test]
+    fn multi_file_lengths() {
+        let info = multi_file_info(&[10, 0, 5], 4);
+        assert_eq!(15, info.total_length());
+        assert_eq!(4, info.piece_len(0));
+        assert_eq!(3, info.piece_len(3));
+        assert_eq!(0, info.piece_len(4));
+    }
+
+    #[test]
+    fn pieces_spanning_files() {
+        let info = multi_file_info(&[10, 0, 5], 4);
+        assert_eq!(vec![slice(0, 0, 4)], info.piece_file_slices(0));
+        assert_eq!(
+            vec![slice(0, 8, 2), slice(2, 0, 2)],
+            info.piece_file_slices(2)
+        );
+        assert_eq!(vec![slice(2, 2, 3)], info.piece_file_slices(3));
+        assert_eq!(vec![slice(0, 9, 1), slice(2, 0, 5)], info.file_slices(9, 6));
+    }
+
+    #[test]
+    fn single_file_slices() {
+        let torrent = Torrent::from_bytes(include_bytes!("../sample.torrent")).unwrap();
+        assert_eq!(92063, torrent.info.total_length());
+        assert_eq!(
+            vec![slice(0, 65536, 26527)],
+            torrent.info.piece_file_slices(2)
+        );
+    }
+
+    #[test]
+    fn announce_list_only() {
+        let tiers = BencodeValue::List(vec![
+            BencodeValue::List(vec![
+                "udp://a.example:80".into(),
+                "udp://b.example:80".into(),
+            ]),
+            BencodeValue::List(vec![]),
+            BencodeValue::List(vec!["http://c.example/announce".into()]),
+        ]);
+        let bytes = dict(vec![("announce-list", tiers), ("info", info_with(vec![]))]).encode();
+        let torrent = Torrent::from_bytes(&bytes).unwrap();
+        assert_eq!(None, torrent.announce);
+        assert_eq!(
+            vec![
+                vec!["udp://a.example:80", "udp://b.example:80"],
+                vec!["http://c.example/announce"]
+            ],
+            torrent.tracker_tiers()
+        );
+    }
+
+    #[test]
+    fn announce_list_takes_precedence() {
+        let tiers = BencodeValue::List(vec![BencodeValue::List(vec!["udp://a.example:80".into()])]);
+        let info = info_with(vec![]);
+        let bytes = dict(vec![
+            ("announce", "http://main.example/announce".into()),
+            ("announce-list", tiers),
+            ("info", info.clone()),
+        ])
+        .encode();
+        let torrent = Torrent::from_bytes(&bytes).unwrap();
+        assert_eq!(vec![vec!["udp://a.example:80"]], torrent.tracker_tiers());
+
+        let torrent = Torrent::from_bytes(&torrent_with(&info)).unwrap();
+        assert_eq!(
+            vec![vec!["http://tracker.example/announce"]],
+            torrent.tracker_tiers()
+        );
+    }
+
+    #[test]
+    fn sample_info_hash() {
+        let torrent = Torrent::from_bytes(include_bytes!("../sample.torrent")).unwrap();
+        assert_eq!(
+            "d69f91e6b2ae4c542468d1073a71d4ea13879a7f",
+            hex::encode(torrent.info_hash())
+        );
+    }
+
+    #[test]
+    fn info_hash_covers_unmodelled_keys() {
+        let info = info_with(vec![
+            ("md5sum", "0cc175b9c0f1b6a831c399e269772661".into()),
+            ("name.utf-8", "a.txt".into()),
+            ("private", BencodeValue::Int(1)),
+            ("source", "TRACKER".into()),
+            ("x-unknown-extension", BencodeValue::List(vec![1.into()])),
+        ]);
+        let torrent = Torrent::from_bytes(&torrent_with(&info)).unwrap();
+        let expected: [u8; 20] = Sha1::digest(info.encode()).into();
+        assert_eq!(expected, torrent.info_hash());
+        assert_eq!(info.encode(), torrent.info_bytes());
+
+        let plain = Torrent::from_bytes(&torrent_with(&info_with(vec![]))).unwrap();
+        assert_ne!(plain.info_hash(), torrent.info_hash());
+    }
+
+    #[test]
+    fn info_hash_ignores_outer_keys() {
+        let info = info_with(vec![("private", BencodeValue::Int(1))]);
+        let mut outer = torrent_with(&info);
+        let a = Torrent::from_bytes(&outer).unwrap();
+        outer = dict(vec![
+            ("announce", "udp://other.example:80".into()),
+            ("comment", "rewritten".into()),
+            ("info", info),
+        ])
+        .encode();
+        let b = Torrent::from_bytes(&outer).unwrap();
+        assert_eq!(a.info_hash(), b.info_hash());
+    }
+
+    #[test]
+    fn load_torrents_with_unsorted_keys() {
+        // Written by hand, since the encoder always sorts: `name` comes before `length` and
+        // `info` before `announce`.
+        let info =
+            b"d4:name5:a.txt6:lengthi3e12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let mut bytes = b"d4:info".to_vec();
+        bytes.extend_from_slice(info);
+        bytes.extend_from_slice(b"8:announce31:http://tracker.example/announcee");
+        let torrent = Torrent::from_bytes(&bytes).unwrap();
+        assert_eq!("a.txt", torrent.info.name);
+        assert_eq!(&info[..], torrent.info_bytes());
+        let expected: [u8; 20] = Sha1::digest(info).into();
+        assert_eq!(expected, torrent.info_hash());
+    }
+
+    #[test]
+    fn reject_torrents_without_info() {
+        let bytes = dict(vec![("announce", "http://tracker.example/announce".into())]).encode();
+        assert!(matches!(
+            Torrent::from_bytes(&bytes),
+            Err(BittorrentError::MissingInfo)
+        ));
+    }
+
+    #[test]
+    fn parse_multi_file_torrents() {
+        let files = BencodeValue::List(vec![
+            dict(vec![
+                ("length", BencodeValue::Int(2)),
+                ("path", BencodeValue::List(vec!["a.txt".into()])),
+            ]),
+            dict(vec![
+                ("length", BencodeValue::Int(1)),
+                (
+                    "path",
+                    BencodeValue::List(vec!["sub".into(), "b.txt".into()]),
+                ),
+            ]),
+        ]);
+        let info = dict(vec![
+            ("files", files),
+            ("name", "dir".into()),
+            ("piece length", BencodeValue::Int(16384)),
+            ("pieces", BencodeValue::Bytes(vec![7; 20])),
+        ]);
+        let torrent = Torrent::from_bytes(&torrent_with(&info)).unwrap();
+        assert_eq!(vec![2, 1], torrent.info.file_lengths());
+        let Keys::MultiFile { files } = &torrent.info.keys else {
+            panic!("expected a multi-file torrent");
+        };
+        assert_eq!(vec!["sub", "b.txt"], files[1].path);
+        assert_eq!(
+            Some("http://tracker.example/announce"),
+            torrent.announce.as_deref()
+        );
+    }
+
+    #[test]
+    fn reject_malformed_torrents() {
+        let cases = [
+            info_with(vec![("name", BencodeValue::Int(1))]),
+            info_with(vec![("piece length", BencodeValue::Int(0))]),
+            info_with(vec![("pieces", BencodeValue::Bytes(vec![7; 21]))]),
+            info_with(vec![("length", BencodeValue::Int(-3))]),
+            dict(vec![
+                ("name", "a.txt".into()),
+                ("piece length", BencodeValue::Int(16384)),
+                ("pieces", BencodeValue::Bytes(vec![7; 20])),
+            ]),
+            dict(vec![
+                ("files", BencodeValue::List(vec![BencodeValue::Int(1)])),
+                ("name", "dir".into()),
+                ("piece length", BencodeValue::Int(16384)),
+                ("pieces", BencodeValue::Bytes(vec![7; 20])),
+            ]),
+        ];
+        for info in cases {
+            assert!(matches!(
+                Torrent::from_bytes(&torrent_with(&info)),
+                Err(BittorrentError::MalformedTorrent(_))
+            ));
+        }
+
+        let mut bytes = torrent_with(&info_with(vec![]));
+        bytes.pop();
+        assert!(matches!(
+            Torrent::from_bytes(&bytes),
+            Err(BittorrentError::DecodeError(_))
+        ));
+    }
+}
@@ -1,11 +1,48 @@
-use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::OnceLock;
+use std::time::Duration;
 
-use self::peers::Peers;
+use serde::Serialize;
+use thiserror::Error;
+
+pub use self::peers::{PeerInfo, Peers};
+use self::udp::UdpTrackerClient;
+use crate::bencode::{self, BencodeValue};
+use crate::error::BittorrentError;
+use crate::peer_id::PeerId;
+use crate::random::Rng;
+
+pub mod announcer;
+#[cfg(test)]
+mod testing;
+pub mod udp;
+
+/// How long to wait for an HTTP tracker to accept a connection.
+const HTTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long an HTTP tracker request may take as a whole, including reading the reply.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long [`TrackerTiers::announce`] waits for each tracker by default, and [`scrape`] for
+/// its tracker, which also cuts the UDP retransmission schedule short.
+const TRACKER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Process-wide HTTP client, so a tracker that never answers can't stall a request forever.
+fn http_client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .connect_timeout(HTTP_CONNECT_TIMEOUT)
+            .timeout(HTTP_TIMEOUT)
+            .build()
+            .expect("HTTP client settings are valid")
+    })
+}
 
 #[derive(Debug, Clone, Serialize)]
 pub struct TrackerRequest {
-    /// Client unique identifier, a string of length 20.
-    pub peer_id: String,
+    /// Client unique identifier, percent-encoded as raw bytes by [`TrackerUrl`].
+    #[serde(skip)]
+    pub peer_id: PeerId,
     /// Port client is listening on.
     pub port: u16,
     /// Total amount uploaded so far.
@@ -16,87 +53,961 @@ pub struct TrackerRequest {
     pub left: usize,
     /// Whether the peer list should use compact representation.
     pub compact: u8,
+    /// Lifecycle event, left out of regular re-announces.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<Event>,
+    /// Number of peers wanted, the tracker picks when absent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub numwant: Option<u32>,
+    /// Random value that lets the tracker recognise us if our IP address changes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<u32>,
+    /// The `tracker id` of a previous response.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trackerid: Option<String>,
+    /// Our address, if it differs from the one the request comes from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>,
+}
+
+impl TrackerRequest {
+    /// A compact request without an event or any optional parameters.
+    pub fn new(peer_id: PeerId, port: u16, left: usize) -> Self {
+        Self {
+            peer_id,
+            port,
+            uploaded: 0,
+            downloaded: 0,
+            left,
+            compact: 1,
+            event: None,
+            numwant: None,
+            key: None,
+            trackerid: None,
+            ip: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    /// The first request to the tracker.
+    Started,
+    /// The download has just finished.
+    Completed,
+    /// The client is shutting down gracefully.
+    Stopped,
 }
 
-pub fn urlencode(t: &[u8; 20]) -> String {
-    let mut encoded = String::with_capacity(3 * t.len());
-    for &byte in t {
-        encoded.push('%');
-        encoded.push_str(&hex::encode([byte]));
+/// Percent-encodes arbitrary bytes, leaving only the unreserved characters of RFC 3986 as is.
+pub fn urlencode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(3 * bytes.len());
+    for &byte in bytes {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push('%');
+            encoded.push_str(&hex::encode_upper([byte]));
+        }
     }
     encoded
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// Appends query parameters to a tracker URL.
+///
+/// Announce URLs of private trackers often carry their own query, such as `?passkey=...`, so
+/// parameters are joined with `?` or `&` as needed and any fragment is kept at the end.
+#[derive(Debug, Clone)]
+pub struct TrackerUrl {
+    url: String,
+    fragment: String,
+}
+
+impl TrackerUrl {
+    pub fn new(url: &str) -> Self {
+        let (url, fragment) = match url.find('#') {
+            Some(i) => url.split_at(i),
+            None => (url, ""),
+        };
+        Self {
+            url: url.to_string(),
+            fragment: fragment.to_string(),
+        }
+    }
+
+    /// Adds a parameter whose value is percent-encoded byte by byte.
+    pub fn param(&mut self, name: &str, value: impl AsRef<[u8]>) -> &mut Self {
+        self.query(&format!(
+            "{}={}",
+            urlencode(name.as_bytes()),
+            urlencode(value.as_ref())
+        ))
+    }
+
+    /// Adds `key=value` pairs that are already encoded, such as the output of `serde_urlencoded`.
+    pub fn query(&mut self, encoded: &str) -> &mut Self {
+        if encoded.is_empty() {
+            return self;
+        }
+        match self.url.find('?') {
+            None => self.url.push('?'),
+            Some(i) if i + 1 == self.url.len() || self.url.ends_with('&') => {}
+            Some(_) => self.url.push('&'),
+        }
+        self.url.push_str(encoded);
+        self
+    }
+
+    pub fn build(&self) -> String {
+        format!("{}{}", self.url, self.fragment)
+    }
+
+    /// The announce URL for `request`, including the binary `info_hash` and `peer_id`.
+    pub fn announce(
+        announce: &str,
+        info_hash: &[u8; 20],
+        request: &TrackerRequest,
+    ) -> Result<String, BittorrentError> {
+        Ok(Self::new(announce)
+            .param("info_hash", info_hash)
+            .param("peer_id", request.peer_id.as_bytes())
+            .query(&serde_urlencoded::to_string(request)?)
+            .build())
+    }
+}
+
+/// Sends `request` to the tracker at `announce`, over UDP for `udp://` URLs and HTTP otherwise.
+pub async fn announce(
+    announce: &str,
+    info_hash: &[u8; 20],
+    request: &TrackerRequest,
+) -> Result<TrackerResponse, BittorrentError> {
+    if announce.starts_with("udp://") {
+        UdpTrackerClient::shared()
+            .announce(announce, info_hash, request)
+            .await
+    } else {
+        announce_http(announce, info_hash, request).await
+    }
+}
+
+async fn announce_http(
+    announce: &str,
+    info_hash: &[u8; 20],
+    request: &TrackerRequest,
+) -> Result<TrackerResponse, BittorrentError> {
+    let tracker_url = TrackerUrl::announce(announce, info_hash, request)?;
+    // Trackers may send failure replies with an error status, so the body is parsed regardless.
+    let response = http_client().get(tracker_url).send().await?;
+    let response = response.bytes().await?;
+    TrackerReply::from_bytes(&response)?.into_result()
+}
+
+/// Swarm statistics for one torrent, as reported by a tracker scrape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrapeResponse {
+    /// Number of peers with the entire file, i.e. seeders.
+    pub complete: usize,
+    /// Number of non-seeder peers, i.e. leechers.
+    pub incomplete: usize,
+    /// Total number of times the tracker has registered a completion.
+    pub downloaded: usize,
+}
+
+/// Derives the scrape URL from an HTTP announce URL.
+///
+/// By convention this only works when the last path segment starts with `announce`, which is
+/// then replaced by `scrape`. UDP trackers use the same URL for both.
+pub fn scrape_url(announce: &str) -> Option<String> {
+    if announce.starts_with("udp://") {
+        return Some(announce.to_string());
+    }
+    let path_end = announce.find('?').unwrap_or(announce.len());
+    let segment_start = announce[..path_end].rfind('/')? + 1;
+    if !announce[segment_start..path_end].starts_with("announce") {
+        return None;
+    }
+    Some(format!(
+        "{}scrape{}",
+        &announce[..segment_start],
+        &announce[segment_start + "announce".len()..]
+    ))
+}
+
+/// Asks the tracker at `announce` for swarm statistics of each torrent, giving up if it
+/// doesn't respond within `TRACKER_TIMEOUT`.
+///
+/// Torrents the tracker doesn't know about are missing from the result.
+pub async fn scrape(
+    announce: &str,
+    info_hashes: &[[u8; 20]],
+) -> Result<HashMap<[u8; 20], ScrapeResponse>, BittorrentError> {
+    scrape_within(TRACKER_TIMEOUT, announce, info_hashes).await
+}
+
+async fn scrape_within(
+    timeout: Duration,
+    announce: &str,
+    info_hashes: &[[u8; 20]],
+) -> Result<HashMap<[u8; 20], ScrapeResponse>, BittorrentError> {
+    let url = scrape_url(announce)
+        .ok_or_else(|| BittorrentError::ScrapeUnsupported(announce.to_string()))?;
+    let scrape = async {
+        if url.starts_with("udp://") {
+            return UdpTrackerClient::shared().scrape(&url, info_hashes).await;
+        }
+
+        let mut url = TrackerUrl::new(&url);
+        for info_hash in info_hashes {
+            url.param("info_hash", info_hash);
+        }
+        let response = http_client().get(url.build()).send().await?;
+        let response = response.bytes().await?;
+        parse_scrape(&response)
+    };
+    tokio::time::timeout(timeout, scrape)
+        .await
+        .map_err(|_| BittorrentError::TrackerTimeout(announce.to_string()))?
+}
+
+fn parse_scrape(response: &[u8]) -> Result<HashMap<[u8; 20], ScrapeResponse>, BittorrentError> {
+    let malformed = |reason| BittorrentError::TrackerError(TrackerError::Malformed(reason));
+    // Trackers commonly leave dictionary keys unsorted.
+    let response = bencode::decode_lenient(response)?;
+    if let Some(reason) = response
+        .get(b"failure reason")
+        .and_then(BencodeValue::as_bytes)
+    {
+        return Err(TrackerError::Failure(TrackerFailure {
+            reason: String::from_utf8_lossy(reason).into_owned(),
+            retry: Retry::Unspecified,
+        })
+        .into());
+    }
+    let files = response
+        .get(b"files")
+        .and_then(BencodeValue::as_dict)
+        .ok_or(malformed("scrape response has no files dictionary"))?;
+
+    let mut stats = HashMap::new();
+    for (info_hash, file) in files {
+        let info_hash: [u8; 20] = info_hash
+            .as_slice()
+            .try_into()
+            .map_err(|_| malformed("scraped info hash is not 20 bytes"))?;
+        let field = |key: &[u8]| {
+            file.get(key)
+                .and_then(BencodeValue::as_int)
+                .and_then(|n| usize::try_from(n).ok())
+                .ok_or(malformed("scrape entry is missing a count"))
+        };
+        stats.insert(
+            info_hash,
+            ScrapeResponse {
+                complete: field(b"complete")?,
+                incomplete: field(b"incomplete")?,
+                downloaded: field(b"downloaded")?,
+            },
+        );
+    }
+    Ok(stats)
+}
+
+/// Trackers grouped into tiers, tried in order as described in BEP 12.
+#[derive(Debug, Clone)]
+pub struct TrackerTiers {
+    tiers: Vec<Vec<String>>,
+    /// How long to wait for each tracker to respond.
+    timeout: Duration,
+}
+
+impl TrackerTiers {
+    /// Shuffles the trackers within each tier, so that load is spread across them.
+    pub fn new(tiers: Vec<Vec<String>>) -> Self {
+        let mut rng = Rng::new();
+        let mut tiers: Vec<Vec<String>> = tiers.into_iter().filter(|t| !t.is_empty()).collect();
+        for tier in &mut tiers {
+            rng.shuffle(tier);
+        }
+        Self {
+            tiers,
+            timeout: TRACKER_TIMEOUT,
+        }
+    }
+
+    /// Changes how long each tracker is given to respond to an announce.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn tiers(&self) -> &[Vec<String>] {
+        &self.tiers
+    }
+
+    /// Every tracker, in the order they should be tried.
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.tiers.iter().flatten().map(String::as_str)
+    }
+
+    /// Moves a tracker that responded to the front of its tier.
+    pub fn promote(&mut self, url: &str) {
+        for tier in &mut self.tiers {
+            if let Some(position) = tier.iter().position(|u| u == url) {
+                let tracker = tier.remove(position);
+                tier.insert(0, tracker);
+                return;
+            }
+        }
+    }
+
+    /// Announces to the trackers in order until one responds, as BEP 12 describes, and moves
+    /// that one to the front of its tier. Only fails if none of them respond.
+    ///
+    /// Trackers that don't respond within the timeout are skipped, so one unreachable tracker
+    /// only delays the others by that much.
+    pub async fn announce(
+        &mut self,
+        info_hash: &[u8; 20],
+        request: &TrackerRequest,
+    ) -> Result<(String, TrackerResponse), BittorrentError> {
+        let mut last_error = BittorrentError::NoTrackers;
+        let urls: Vec<String> = self.iter().map(String::from).collect();
+        for url in urls {
+            match tokio::time::timeout(self.timeout, announce(&url, info_hash, request)).await {
+                Ok(Ok(response)) => {
+                    self.promote(&url);
+                    return Ok((url, response));
+                }
+                Ok(Err(e)) => last_error = e,
+                Err(_) => last_error = BittorrentError::TrackerTimeout(url),
+            }
+        }
+        Err(last_error)
+    }
+}
+
+/// Merges the peers of several tracker responses, dropping duplicate addresses.
+pub fn merge_peers<'a>(responses: impl IntoIterator<Item = &'a TrackerResponse>) -> Vec<PeerInfo> {
+    let mut seen = HashSet::new();
+    responses
+        .into_iter()
+        .flat_map(|response| response.peers.0.iter().copied())
+        .filter(|peer| seen.insert(peer.addr))
+        .collect()
+}
+
+/// Successful reply to an announce.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct TrackerResponse {
     /// Indicates how often a client should make requests to the tracker (in seconds).
     pub interval: usize,
-    /// Contains a list of peers that client can connect to. Each peer is represented using 6 bytes.
-    /// The first 4 bytes are the peer's IP address and the last 2 bytes are the peer's port number.
+    /// Clients must not re-announce more often than this (in seconds).
+    pub min_interval: Option<usize>,
+    /// Opaque value to send back in the `trackerid` parameter of subsequent announces.
+    pub tracker_id: Option<Vec<u8>>,
+    /// Number of seeders in the swarm.
+    pub complete: Option<usize>,
+    /// Number of leechers in the swarm.
+    pub incomplete: Option<usize>,
+    /// Something the tracker wants the user to know, the announce still succeeded.
+    pub warning_message: Option<String>,
+    /// Contains a list of peers that client can connect to, from both `peers` and `peers6`.
     pub peers: Peers,
 }
 
+/// A tracker reply is either a list of peers or a human-readable reason for refusing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrackerReply {
+    Success(TrackerResponse),
+    Failure(TrackerFailure),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackerFailure {
+    /// Why the tracker refused the request.
+    pub reason: String,
+    /// When the request may be retried, from the `retry in` key of BEP 31.
+    pub retry: Retry,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retry {
+    /// The tracker gave no hint, retrying on the regular schedule is fine.
+    Unspecified,
+    /// Retry after this many minutes.
+    AfterMinutes(u64),
+    /// The request will never succeed.
+    Never,
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum TrackerError {
+    #[error("tracker refused the request: {}", .0.reason)]
+    Failure(TrackerFailure),
+    #[error("malformed tracker response: {0}")]
+    Malformed(&'static str),
+}
+
+impl TrackerError {
+    /// Whether asking the same tracker again later could succeed.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            TrackerError::Failure(failure) => failure.retry != Retry::Never,
+            TrackerError::Malformed(_) => true,
+        }
+    }
+}
+
+impl TrackerReply {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, BittorrentError> {
+        let malformed = |reason| BittorrentError::TrackerError(TrackerError::Malformed(reason));
+        // Trackers commonly leave dictionary keys unsorted.
+        let reply = bencode::decode_lenient(bytes)?;
+        if reply.as_dict().is_none() {
+            return Err(malformed("reply is not a dictionary"));
+        }
+        let string = |key: &[u8]| {
+            reply
+                .get(key)
+                .and_then(BencodeValue::as_bytes)
+                .map(|b| String::from_utf8_lossy(b).into_owned())
+        };
+        let count = |key: &[u8]| {
+            reply
+                .get(key)
+                .and_then(BencodeValue::as_int)
+                .and_then(|n| usize::try_from(n).ok())
+        };
+
+        if let Some(reason) = string(b"failure reason") {
+            let retry = match reply.get(b"retry in") {
+                Some(BencodeValue::Int(minutes)) if *minutes >= 0 => {
+                    Retry::AfterMinutes(*minutes as u64)
+                }
+                Some(v) if v.as_bytes() == Some(b"never") => Retry::Never,
+                _ => Retry::Unspecified,
+            };
+            return Ok(TrackerReply::Failure(TrackerFailure { reason, retry }));
+        }
+
+        let interval = count(b"interval").ok_or(malformed("missing interval"))?;
+        let peers4 = match reply.get(b"peers") {
+            Some(peers) => Some(Peers::from_bencode(peers).ok_or(malformed("malformed peers"))?),
+            None => None,
+        };
+        let peers6 = match reply.get(b"peers6") {
+            Some(peers) => Some(
+                peers
+                    .as_bytes()
+                    .and_then(Peers::from_compact_v6)
+                    .ok_or(malformed("malformed peers6"))?,
+            ),
+            None => None,
+        };
+        if peers4.is_none() && peers6.is_none() {
+            return Err(malformed("missing peers"));
+        }
+        let peers = Peers(peers4.into_iter().chain(peers6).flat_map(|p| p.0).collect());
+        Ok(TrackerReply::Success(TrackerResponse {
+            interval,
+            min_interval: count(b"min interval"),
+            tracker_id: reply
+                .get(b"tracker id")
+                .and_then(BencodeValue::as_bytes)
+                .map(<[u8]>::to_vec),
+            complete: count(b"complete"),
+            incomplete: count(b"incomplete"),
+            warning_message: string(b"warning message"),
+            peers,
+        }))
+    }
+
+    /// Turns a failure reply into a [`TrackerError`].
+    pub fn into_result(self) -> Result<TrackerResponse, BittorrentError> {
+        match self {
+            TrackerReply::Success(response) => Ok(response),
+            TrackerReply::Failure(failure) => Err(TrackerError::Failure(failure).into()),
+        }
+    }
+}
+
 mod peers {
-    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    use crate::bencode::BencodeValue;
+    use crate::peer_id::PeerId;
 
-    use serde::de::{self, Visitor};
-    use serde::{Deserialize, Serialize, Serializer};
+    /// A peer as announced by a tracker.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct PeerInfo {
+        pub addr: SocketAddr,
+        /// Only present in the non-compact format.
+        pub peer_id: Option<PeerId>,
+    }
 
-    #[derive(Debug, Clone)]
-    pub struct Peers(pub Vec<SocketAddrV4>);
-    struct PeersVisitor;
+    impl From<SocketAddr> for PeerInfo {
+        fn from(addr: SocketAddr) -> Self {
+            Self {
+                addr,
+                peer_id: None,
+            }
+        }
+    }
 
-    impl<'de> Visitor<'de> for PeersVisitor {
-        type Value = Peers;
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    pub struct Peers(pub Vec<PeerInfo>);
 
-        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-            formatter.write_str(
-                "6 bytes, first 4 bytes are the peer's IP address and the last 2 bytes are the peer's port number",
-            )
+    impl Peers {
+        /// Parses whichever of the compact or dictionary formats `peers` uses.
+        pub fn from_bencode(peers: &BencodeValue) -> Option<Self> {
+            match peers {
+                BencodeValue::Bytes(bytes) => Self::from_compact_v4(bytes),
+                BencodeValue::List(list) => Some(Self::from_dicts(list)),
+                _ => None,
+            }
         }
 
-        fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
-        where
-            E: de::Error,
-        {
-            if v.len() % 6 != 0 {
-                return Err(E::custom(format!("length is {}", v.len())));
+        /// Parses the compact format, `None` if the length is not a multiple of 6.
+        /// The first 4 bytes are the peer's IP address and the last 2 bytes are the peer's port number.
+        pub fn from_compact_v4(v: &[u8]) -> Option<Self> {
+            if !v.len().is_multiple_of(6) {
+                return None;
             }
 
             let chunks = v
                 .chunks_exact(6)
                 .map(|slice_6| {
-                    SocketAddrV4::new(
-                        Ipv4Addr::new(slice_6[0], slice_6[1], slice_6[2], slice_6[3]),
-                        u16::from_be_bytes([slice_6[4], slice_6[5]]),
-                    )
+                    let ip: [u8; 4] = slice_6[..4].try_into().expect("length 4");
+                    let port = u16::from_be_bytes([slice_6[4], slice_6[5]]);
+                    SocketAddr::from((Ipv4Addr::from(ip), port)).into()
+                })
+                .collect();
+
+            Some(Peers(chunks))
+        }
+
+        /// Parses the compact IPv6 format of BEP 7, `None` if the length is not a multiple of 18.
+        pub fn from_compact_v6(v: &[u8]) -> Option<Self> {
+            if !v.len().is_multiple_of(18) {
+                return None;
+            }
+
+            let chunks = v
+                .chunks_exact(18)
+                .map(|slice_18| {
+                    let ip: [u8; 16] = slice_18[..16].try_into().expect("length 16");
+                    let port = u16::from_be_bytes([slice_18[16], slice_18[17]]);
+                    SocketAddr::from((Ipv6Addr::from(ip), port)).into()
                 })
                 .collect();
 
-            Ok(Peers(chunks))
+            Some(Peers(chunks))
+        }
+
+        /// Parses the original format, a list of dictionaries with `peer id`, `ip` and `port`.
+        ///
+        /// Entries without a literal IP address or a valid port are skipped.
+        pub fn from_dicts(list: &[BencodeValue]) -> Self {
+            let peers = list
+                .iter()
+                .filter_map(|peer| {
+                    let ip = peer.get(b"ip")?.as_str()?.parse().ok()?;
+                    let port = u16::try_from(peer.get(b"port")?.as_int()?).ok()?;
+                    let peer_id = peer
+                        .get(b"peer id")
+                        .and_then(BencodeValue::as_bytes)
+                        .and_then(|id| <[u8; 20]>::try_from(id).ok())
+                        .map(PeerId);
+                    Some(PeerInfo {
+                        addr: SocketAddr::new(ip, port),
+                        peer_id,
+                    })
+                })
+                .collect();
+            Peers(peers)
         }
     }
+}
 
-    impl<'de> Deserialize<'de> for Peers {
-        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-        where
-            D: serde::Deserializer<'de>,
-        {
-            deserializer.deserialize_bytes(PeersVisitor)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tiers(tiers: &[&[&str]]) -> Vec<Vec<String>> {
+        tiers
+            .iter()
+            .map(|tier| tier.iter().map(|u| u.to_string()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn shuffle_within_tiers_only() {
+        let input = tiers(&[&["a", "b", "c", "d"], &[], &["e", "f"]]);
+        for _ in 0..20 {
+            let shuffled = TrackerTiers::new(input.clone());
+            assert_eq!(2, shuffled.tiers().len());
+            let mut first = shuffled.tiers()[0].clone();
+            first.sort();
+            assert_eq!(input[0], first);
+            let mut second = shuffled.tiers()[1].clone();
+            second.sort();
+            assert_eq!(input[2], second);
         }
     }
 
-    impl Serialize for Peers {
-        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-        where
-            S: Serializer,
-        {
-            let mut single_slice = Vec::with_capacity(6 * self.0.len());
-            for peer in &self.0 {
-                single_slice.extend(peer.ip().octets());
-                single_slice.extend(peer.port().to_be_bytes());
-            }
-            serializer.serialize_bytes(&single_slice)
+    #[test]
+    fn promote_within_tier() {
+        let mut tiers = TrackerTiers {
+            tiers: tiers(&[&["a", "b", "c"], &["d", "e"]]),
+            timeout: TRACKER_TIMEOUT,
+        };
+        tiers.promote("c");
+        tiers.promote("e");
+        tiers.promote("missing");
+        assert_eq!(
+            vec!["c", "a", "b", "e", "d"],
+            tiers.iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn derive_scrape_urls() {
+        let cases = [
+            (
+                "http://example.com/announce",
+                Some("http://example.com/scrape"),
+            ),
+            (
+                "http://example.com/x/announce",
+                Some("http://example.com/x/scrape"),
+            ),
+            (
+                "http://example.com/announce.php",
+                Some("http://example.com/scrape.php"),
+            ),
+            (
+                "http://example.com/announce?x2%0644",
+                Some("http://example.com/scrape?x2%0644"),
+            ),
+            (
+                "http://example.com/abc/announce?passkey=a/b",
+                Some("http://example.com/abc/scrape?passkey=a/b"),
+            ),
+            (
+                "udp://tracker.example:1337",
+                Some("udp://tracker.example:1337"),
+            ),
+            ("http://example.com/a", None),
+            (
+                "http://example.com/announce?x=2/4",
+                Some("http://example.com/scrape?x=2/4"),
+            ),
+            ("http://example.com/x%064announce", None),
+            ("http://example.com/announce/", None),
+        ];
+        for (announce, expected) in cases {
+            assert_eq!(
+                expected.map(String::from),
+                scrape_url(announce),
+                "{announce}"
+            );
+        }
+    }
+
+    #[test]
+    fn percent_encode_bytes() {
+        assert_eq!("aZ09-._~", urlencode(b"aZ09-._~"));
+        assert_eq!("%00%20%25%26%3D%2B%FF", urlencode(b"\x00 %&=+\xff"));
+    }
+
+    #[test]
+    fn append_tracker_url_params() {
+        let cases = [
+            (
+                "http://t.example/announce",
+                "http://t.example/announce?a=1&b=%26",
+            ),
+            (
+                "http://t.example:6969/announce.php",
+                "http://t.example:6969/announce.php?a=1&b=%26",
+            ),
+            (
+                "https://t.example/announce?passkey=abc123",
+                "https://t.example/announce?passkey=abc123&a=1&b=%26",
+            ),
+            (
+                "http://t.example/abc123/announce?uk=x&pk=y",
+                "http://t.example/abc123/announce?uk=x&pk=y&a=1&b=%26",
+            ),
+            (
+                "http://t.example/announce?",
+                "http://t.example/announce?a=1&b=%26",
+            ),
+            (
+                "http://t.example/announce?passkey=abc&",
+                "http://t.example/announce?passkey=abc&a=1&b=%26",
+            ),
+            (
+                "http://t.example/announce#frag",
+                "http://t.example/announce?a=1&b=%26#frag",
+            ),
+            (
+                "http://t.example/announce?k=v#frag",
+                "http://t.example/announce?k=v&a=1&b=%26#frag",
+            ),
+        ];
+        for (announce, expected) in cases {
+            let url = TrackerUrl::new(announce)
+                .param("a", "1")
+                .param("b", "&")
+                .build();
+            assert_eq!(expected, url, "{announce}");
         }
+        assert_eq!(
+            "http://t.example/announce?passkey=p",
+            TrackerUrl::new("http://t.example/announce?passkey=p")
+                .query("")
+                .build()
+        );
+    }
+
+    #[test]
+    fn build_announce_url() {
+        let mut peer_id = [0xff; 20];
+        peer_id[..8].copy_from_slice(b"-BT0100-");
+        let request = TrackerRequest {
+            event: Some(Event::Started),
+            ..TrackerRequest::new(PeerId(peer_id), 6881, 100)
+        };
+        let mut info_hash = [b'a'; 20];
+        info_hash[0] = 0x12;
+        info_hash[1] = b' ';
+        let url = TrackerUrl::announce(
+            "http://t.example/announce?passkey=abc",
+            &info_hash,
+            &request,
+        )
+        .unwrap();
+        assert_eq!(
+            format!(
+                "http://t.example/announce?passkey=abc&info_hash=%12%20{}&peer_id=-BT0100-{}\
+                 &port=6881&uploaded=0&downloaded=0&left=100&compact=1&event=started",
+                "a".repeat(18),
+                "%FF".repeat(12)
+            ),
+            url
+        );
+    }
+
+    #[test]
+    fn parse_scrape_files() {
+        let response =
+            b"d5:filesd20:aaaaaaaaaaaaaaaaaaaad8:completei5e10:downloadedi50e10:incompletei10eeee";
+        let stats = parse_scrape(response).unwrap();
+        assert_eq!(
+            ScrapeResponse {
+                complete: 5,
+                incomplete: 10,
+                downloaded: 50,
+            },
+            stats[b"aaaaaaaaaaaaaaaaaaaa"]
+        );
+        assert!(parse_scrape(b"d5:filesdee").unwrap().is_empty());
+        let unsorted =
+            b"d5:filesd20:aaaaaaaaaaaaaaaaaaaad10:incompletei10e8:completei5e10:downloadedi50eeee";
+        assert_eq!(stats, parse_scrape(unsorted).unwrap());
+        assert!(matches!(
+            parse_scrape(b"d14:failure reason4:nopee"),
+            Err(BittorrentError::TrackerError(TrackerError::Failure(_)))
+        ));
+        assert!(matches!(
+            parse_scrape(b"d8:intervali1ee"),
+            Err(BittorrentError::TrackerError(TrackerError::Malformed(_)))
+        ));
+        assert!(parse_scrape(b"d5:filesd3:abcd8:completei5eeee").is_err());
+    }
+
+    #[test]
+    fn parse_successful_reply() {
+        let reply = b"d8:completei9e10:incompletei3e8:intervali1800e12:min intervali60e5:peers6:\x0a\x00\x00\x01\x1a\xe110:tracker id3:abc15:warning message4:slowe";
+        let TrackerReply::Success(response) = TrackerReply::from_bytes(reply).unwrap() else {
+            panic!("expected success");
+        };
+        assert_eq!(
+            TrackerResponse {
+                interval: 1800,
+                min_interval: Some(60),
+                tracker_id: Some(b"abc".to_vec()),
+                complete: Some(9),
+                incomplete: Some(3),
+                warning_message: Some("slow".into()),
+                peers: Peers(vec![peer("10.0.0.1:6881")]),
+            },
+            response
+        );
+
+        let minimal = TrackerReply::from_bytes(b"d8:intervali900e5:peers0:e").unwrap();
+        let unsorted = TrackerReply::from_bytes(b"d5:peers0:8:intervali900ee").unwrap();
+        assert_eq!(minimal, unsorted);
+        assert_eq!(
+            TrackerReply::Success(TrackerResponse {
+                interval: 900,
+                ..Default::default()
+            }),
+            minimal
+        );
+    }
+
+    #[test]
+    fn parse_failure_reply() {
+        let reply = TrackerReply::from_bytes(b"d14:failure reason17:torrent not founde").unwrap();
+        assert_eq!(
+            TrackerReply::Failure(TrackerFailure {
+                reason: "torrent not found".into(),
+                retry: Retry::Unspecified,
+            }),
+            reply
+        );
+        let error = reply.into_result().unwrap_err();
+        assert_eq!(
+            "Tracker error: tracker refused the request: torrent not found",
+            error.to_string()
+        );
+
+        let never = TrackerReply::from_bytes(b"d14:failure reason6:banned8:retry in5:nevere")
+            .unwrap()
+            .into_result()
+            .unwrap_err();
+        let BittorrentError::TrackerError(never) = never else {
+            panic!("expected a tracker error");
+        };
+        assert!(!never.is_retryable());
+
+        let later = TrackerReply::from_bytes(b"d14:failure reason4:busy8:retry ini5ee").unwrap();
+        let TrackerReply::Failure(failure) = later else {
+            panic!("expected failure");
+        };
+        assert_eq!(Retry::AfterMinutes(5), failure.retry);
+        assert!(TrackerError::Failure(failure).is_retryable());
+    }
+
+    #[test]
+    fn reject_malformed_replies() {
+        for reply in [
+            &b"d5:peers0:e"[..],
+            b"d8:intervali1e5:peers5:abcdee",
+            b"d8:intervali-1e5:peers0:e",
+            b"le",
+        ] {
+            assert!(matches!(
+                TrackerReply::from_bytes(reply),
+                Err(BittorrentError::TrackerError(TrackerError::Malformed(_)))
+            ));
+        }
+        assert!(matches!(
+            TrackerReply::from_bytes(b"d8:interval"),
+            Err(BittorrentError::DecodeError(_))
+        ));
+    }
+
+    fn peer(addr: &str) -> PeerInfo {
+        addr.parse::<std::net::SocketAddr>().unwrap().into()
+    }
+
+    #[test]
+    fn parse_dictionary_peers() {
+        let reply = b"d8:intervali60e5:peersld2:ip8:10.0.0.17:peer id20:-XX0001-abcdefghijkl4:porti6881eed2:ip3:::14:porti51413eed2:ip11:example.com4:porti1eed2:ip8:10.0.0.24:porti70000eeee";
+        let response = TrackerReply::from_bytes(reply)
+            .unwrap()
+            .into_result()
+            .unwrap();
+        assert_eq!(
+            Peers(vec![
+                PeerInfo {
+                    addr: "10.0.0.1:6881".parse().unwrap(),
+                    peer_id: Some(PeerId(*b"-XX0001-abcdefghijkl")),
+                },
+                peer("[::1]:51413"),
+            ]),
+            response.peers
+        );
+    }
+
+    #[test]
+    fn parse_ipv6_peers() {
+        let mut reply = b"d8:intervali60e5:peers6:\x7f\x00\x00\x01\x00\x506:peers618:".to_vec();
+        reply.extend([0x20, 0x01, 0x0d, 0xb8]);
+        reply.extend([0; 11]);
+        reply.extend([1, 0x1a, 0xe1]);
+        reply.push(b'e');
+        let response = TrackerReply::from_bytes(&reply)
+            .unwrap()
+            .into_result()
+            .unwrap();
+        assert_eq!(
+            Peers(vec![peer("127.0.0.1:80"), peer("[2001:db8::1]:6881")]),
+            response.peers
+        );
+
+        let only_v6 = b"d8:intervali60e6:peers60:e";
+        assert!(TrackerReply::from_bytes(only_v6).is_ok());
+        let bad_v6 = b"d8:intervali60e6:peers66:abcdefe";
+        assert!(TrackerReply::from_bytes(bad_v6).is_err());
+    }
+
+    #[test]
+    fn merge_duplicate_peers() {
+        let a = TrackerResponse {
+            peers: Peers(vec![peer("1.1.1.1:1"), peer("[::2]:2")]),
+            ..Default::default()
+        };
+        let b = TrackerResponse {
+            peers: Peers(vec![peer("[::2]:2"), peer("3.3.3.3:3")]),
+            ..Default::default()
+        };
+        assert_eq!(3, merge_peers([&a, &b]).len());
+    }
+
+    #[tokio::test]
+    async fn announce_without_trackers() {
+        let request = TrackerRequest::new(PeerId(*b"00112233445566778899"), 6881, 0);
+        let mut tiers = TrackerTiers::new(Vec::new());
+        assert!(matches!(
+            tiers.announce(&[0; 20], &request).await,
+            Err(BittorrentError::NoTrackers)
+        ));
+    }
+
+    #[tokio::test]
+    async fn give_up_on_silent_scrapes() {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+        assert!(matches!(
+            scrape_within(Duration::from_millis(200), &url, &[[0; 20]]).await,
+            Err(BittorrentError::TrackerTimeout(u)) if u == url
+        ));
+    }
+
+    #[tokio::test]
+    async fn announce_until_a_tracker_responds() {
+        let body = b"d8:intervali60e5:peers6:\x0a\x00\x00\x01\x1a\xe1e";
+        let silent = testing::silent_tracker().await;
+        let (first, mut first_queries) = testing::fake_http_tracker(body.to_vec()).await;
+        let (second, mut second_queries) = testing::fake_http_tracker(body.to_vec()).await;
+        let mut trackers = TrackerTiers::new(vec![
+            vec![first.clone(), silent.clone()],
+            vec![second.clone()],
+        ]);
+        trackers.promote(&silent);
+        trackers.set_timeout(Duration::from_millis(200));
+
+        let request = TrackerRequest::new(PeerId(*b"00112233445566778899"), 6881, 0);
+        let (url, response) = trackers.announce(&[0; 20], &request).await.unwrap();
+        assert_eq!(first, url);
+        assert_eq!(vec![peer("10.0.0.1:6881")], response.peers.0);
+        assert!(first_queries.try_recv().is_ok());
+        assert!(second_queries.try_recv().is_err());
+        assert_eq!(&[vec![first, silent], vec![second]], trackers.tiers());
     }
 }
@@ -0,0 +1,421 @@
+//! Keeps the trackers informed for the whole lifetime of a download.
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use super::{Event, PeerInfo, TrackerRequest, TrackerResponse, TrackerTiers};
+use crate::peer_id::PeerId;
+use crate::random::Rng;
+
+/// Longest pause between attempts while no tracker responds.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30 * 60);
+
+/// Transfer counters shared between the download engine and the announcer.
+#[derive(Debug, Default)]
+pub struct TransferStats {
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    left: AtomicU64,
+}
+
+impl TransferStats {
+    pub fn new(left: u64) -> Self {
+        Self {
+            left: AtomicU64::new(left),
+            ..Default::default()
+        }
+    }
+
+    pub fn add_uploaded(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Records a verified piece, which also reduces what is left.
+    pub fn add_downloaded(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+        // Never underflows, even if a piece is counted twice.
+        let _ = self
+            .left
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                Some(left.saturating_sub(bytes))
+            });
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    pub fn left(&self) -> u64 {
+        self.left.load(Ordering::Relaxed)
+    }
+}
+
+/// Periodically announces a torrent and passes newly discovered peers on.
+#[derive(Debug)]
+pub struct Announcer {
+    tiers: TrackerTiers,
+    info_hash: [u8; 20],
+    peer_id: PeerId,
+    port: u16,
+    stats: Arc<TransferStats>,
+    /// Delay before the first retry when no tracker responds, doubled on every failure.
+    pub retry_delay: Duration,
+    /// How long to wait for trackers to acknowledge `stopped` on shutdown.
+    pub stop_timeout: Duration,
+}
+
+enum Command {
+    Completed,
+    Stop,
+}
+
+/// Controls a running announcer.
+#[derive(Debug)]
+pub struct AnnouncerHandle {
+    commands: mpsc::UnboundedSender<Command>,
+    task: JoinHandle<()>,
+}
+
+impl AnnouncerHandle {
+    /// Tells the trackers that the last piece has been verified.
+    pub fn completed(&self) {
+        let _ = self.commands.send(Command::Completed);
+    }
+
+    /// Sends `stopped` to the trackers, preceded by `completed` if that is still pending, and
+    /// waits for the announcer to exit, which takes at most the announcer's `stop_timeout`.
+    pub async fn stop(self) {
+        let _ = self.commands.send(Command::Stop);
+        let _ = self.task.await;
+    }
+}
+
+impl Announcer {
+    pub fn new(
+        tiers: TrackerTiers,
+        info_hash: [u8; 20],
+        peer_id: PeerId,
+        port: u16,
+        stats: Arc<TransferStats>,
+    ) -> Self {
+        Self {
+            tiers,
+            info_hash,
+            peer_id,
+            port,
+            stats,
+            retry_delay: Duration::from_secs(15),
+            stop_timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Starts announcing in the background. Every peer is only reported once.
+    pub fn spawn(self) -> (AnnouncerHandle, mpsc::UnboundedReceiver<PeerInfo>) {
+        let (commands, commands_rx) = mpsc::unbounded_channel();
+        let (peers, peers_rx) = mpsc::unbounded_channel();
+        let task = tokio::spawn(self.run(commands_rx, peers));
+        (AnnouncerHandle { commands, task }, peers_rx)
+    }
+
+    async fn run(
+        mut self,
+        mut commands: mpsc::UnboundedReceiver<Command>,
+        peers: mpsc::UnboundedSender<PeerInfo>,
+    ) {
+        let key = Rng::new().next_u32();
+        let mut event = Some(Event::Started);
+        let mut tracker_id = None;
+        let mut announced = false;
+        let mut seen = HashSet::new();
+        let mut retry_delay = self.retry_delay;
+        let mut min_interval = Duration::ZERO;
+        // Set when `completed` arrives, until the next announce carries it.
+        let mut completed = false;
+        // When to give up on the trackers once asked to stop.
+        let mut stop_deadline = None;
+
+        // Commands are handled while announcing and waiting alike, so stopping never waits on
+        // a slow tracker for longer than `stop_timeout`.
+        'run: loop {
+            let request = self.request(event, key, tracker_id.clone());
+            let last_announce = Instant::now();
+            let result = {
+                let announce = self.tiers.announce(&self.info_hash, &request);
+                tokio::pin!(announce);
+                loop {
+                    tokio::select! {
+                        result = &mut announce => break result,
+                        command = commands.recv() => match command {
+                            Some(Command::Completed) => completed = true,
+                            Some(Command::Stop) | None => {
+                                // The trackers may have seen this announce already, so it is
+                                // finished rather than repeated.
+                                let deadline = Instant::now() + self.stop_timeout;
+                                stop_deadline = Some(deadline);
+                                match tokio::time::timeout_at(deadline, &mut announce).await {
+                                    Ok(result) => break result,
+                                    Err(_) => break 'run,
+                                }
+                            }
+                        },
+                    }
+                }
+            };
+            let wait = match result {
+                Ok((_, response)) => {
+                    announced = true;
+                    event = None;
+                    retry_delay = self.retry_delay;
+                    if let Some(id) = &response.tracker_id {
+                        tracker_id = Some(String::from_utf8_lossy(id).into_owned());
+                    }
+                    for peer in response.peers.0.iter().copied() {
+                        if seen.insert(peer.addr) {
+                            let _ = peers.send(peer);
+                        }
+                    }
+                    let (interval, min) = next_interval(&response);
+                    min_interval = min;
+                    interval
+                }
+                Err(_) => {
+                    // Keep the pending event so it is delivered once a tracker responds.
+                    let wait = retry_delay;
+                    retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+                    wait
+                }
+            };
+            if stop_deadline.is_some() {
+                break;
+            }
+
+            let mut next_announce = last_announce + wait;
+            loop {
+                if completed {
+                    // Completion is announced as soon as the trackers allow.
+                    event = Some(Event::Completed);
+                    completed = false;
+                    next_announce = next_announce.min(last_announce + min_interval);
+                }
+                tokio::select! {
+                    _ = tokio::time::sleep_until(next_announce) => break,
+                    command = commands.recv() => match command {
+                        Some(Command::Completed) => completed = true,
+                        Some(Command::Stop) | None => break 'run,
+                    },
+                }
+            }
+        }
+
+        if completed {
+            event = Some(Event::Completed);
+        }
+        if announced {
+            let deadline = stop_deadline.unwrap_or_else(|| Instant::now() + self.stop_timeout);
+            let _ = tokio::time::timeout_at(deadline, async {
+                if event == Some(Event::Completed) {
+                    let request = self.request(event, key, tracker_id.clone());
+                    let _ = self.tiers.announce(&self.info_hash, &request).await;
+                }
+                let request = TrackerRequest {
+                    numwant: Some(0),
+                    ..self.request(Some(Event::Stopped), key, tracker_id)
+                };
+                let _ = self.tiers.announce(&self.info_hash, &request).await;
+            })
+            .await;
+        }
+    }
+
+    fn request(&self, event: Option<Event>, key: u32, trackerid: Option<String>) -> TrackerRequest {
+        TrackerRequest {
+            uploaded: self.stats.uploaded() as usize,
+            downloaded: self.stats.downloaded() as usize,
+            event,
+            key: Some(key),
+            trackerid,
+            ..TrackerRequest::new(self.peer_id, self.port, self.stats.left() as usize)
+        }
+    }
+}
+
+/// Returns the tracker's interval, never shorter than what it allows, along with its
+/// `min interval`.
+fn next_interval(response: &TrackerResponse) -> (Duration, Duration) {
+    let min_interval = response.min_interval.unwrap_or(0);
+    let interval = response.interval.max(min_interval).max(1);
+    (
+        Duration::from_secs(interval as u64),
+        Duration::from_secs(min_interval as u64),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracker::testing::{fake_http_tracker, param, silent_tracker};
+
+    fn announcer(tiers: TrackerTiers) -> Announcer {
+        Announcer::new(
+            tiers,
+            [1; 20],
+            PeerId(*b"00112233445566778899"),
+            6881,
+            Arc::new(TransferStats::new(100)),
+        )
+    }
+
+    #[tokio::test]
+    async fn announce_lifecycle() {
+        let body = b"d8:intervali1e5:peers12:\x0a\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\x1a\xe110:tracker id2:t1e";
+        let (url, mut queries) = fake_http_tracker(body.to_vec()).await;
+        let stats = Arc::new(TransferStats::new(100));
+        let announcer = Announcer::new(
+            TrackerTiers::new(vec![vec![url]]),
+            [1; 20],
+            PeerId(*b"00112233445566778899"),
+            6881,
+            stats.clone(),
+        );
+        let (handle, mut peers) = announcer.spawn();
+
+        let started = queries.recv().await.unwrap();
+        assert_eq!(Some("started"), param(&started, "event"));
+        assert_eq!(Some("100"), param(&started, "left"));
+        assert_eq!(None, param(&started, "trackerid"));
+        let key = param(&started, "key").unwrap().to_string();
+        assert_eq!(
+            "10.0.0.1:6881",
+            peers.recv().await.unwrap().addr.to_string()
+        );
+        assert_eq!(
+            "10.0.0.2:6881",
+            peers.recv().await.unwrap().addr.to_string()
+        );
+
+        stats.add_downloaded(60);
+        let regular = queries.recv().await.unwrap();
+        assert_eq!(None, param(&regular, "event"));
+        assert_eq!(Some("60"), param(&regular, "downloaded"));
+        assert_eq!(Some("40"), param(&regular, "left"));
+        assert_eq!(Some("t1"), param(&regular, "trackerid"));
+        assert_eq!(Some(key.as_str()), param(&regular, "key"));
+
+        stats.add_downloaded(40);
+        handle.completed();
+        let completed = queries.recv().await.unwrap();
+        assert_eq!(Some("completed"), param(&completed, "event"));
+        assert_eq!(Some("0"), param(&completed, "left"));
+
+        handle.stop().await;
+        let stopped = queries.recv().await.unwrap();
+        assert_eq!(Some("stopped"), param(&stopped, "event"));
+        assert_eq!(Some("0"), param(&stopped, "numwant"));
+
+        // Peers that were already reported are not sent again.
+        assert!(peers.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn announce_completed_when_stopping_right_away() {
+        let body = b"d8:intervali1800e12:min intervali60e5:peers0:e";
+        let (url, mut queries) = fake_http_tracker(body.to_vec()).await;
+        let (handle, _peers) = announcer(TrackerTiers::new(vec![vec![url]])).spawn();
+        let started = queries.recv().await.unwrap();
+        assert_eq!(Some("started"), param(&started, "event"));
+
+        // `min interval` would hold `completed` back, but shutting down sends it anyway.
+        handle.completed();
+        tokio::time::timeout(Duration::from_secs(5), handle.stop())
+            .await
+            .unwrap();
+        let completed = queries.recv().await.unwrap();
+        assert_eq!(Some("completed"), param(&completed, "event"));
+        let stopped = queries.recv().await.unwrap();
+        assert_eq!(Some("stopped"), param(&stopped, "event"));
+    }
+
+    #[tokio::test]
+    async fn skip_unresponsive_trackers() {
+        let body = b"d8:intervali1800e5:peers6:\x0a\x00\x00\x01\x1a\xe1e";
+        let (url, mut queries) = fake_http_tracker(body.to_vec()).await;
+        let mut tiers = TrackerTiers::new(vec![vec![silent_tracker().await], vec![url]]);
+        tiers.set_timeout(Duration::from_millis(200));
+        let (handle, mut peers) = announcer(tiers).spawn();
+
+        let peer = tokio::time::timeout(Duration::from_secs(5), peers.recv())
+            .await
+            .expect("the responsive tracker should be reached")
+            .unwrap();
+        assert_eq!("10.0.0.1:6881", peer.addr.to_string());
+        handle.stop().await;
+        assert_eq!(
+            Some("started"),
+            param(&queries.recv().await.unwrap(), "event")
+        );
+    }
+
+    #[tokio::test]
+    async fn stop_during_slow_announce() {
+        let tiers = TrackerTiers::new(vec![vec![silent_tracker().await]]);
+        let announcer = Announcer {
+            stop_timeout: Duration::from_millis(200),
+            ..announcer(tiers)
+        };
+        let (handle, _peers) = announcer.spawn();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        tokio::time::timeout(Duration::from_secs(2), handle.stop())
+            .await
+            .expect("stop should give up on the announce after stop_timeout");
+    }
+
+    #[tokio::test]
+    async fn skip_stopped_when_never_announced() {
+        let announcer = Announcer::new(
+            TrackerTiers::new(vec![vec!["http://127.0.0.1:1/announce".into()]]),
+            [1; 20],
+            PeerId(*b"00112233445566778899"),
+            6881,
+            Arc::new(TransferStats::new(1)),
+        );
+        let (handle, _peers) = announcer.spawn();
+        tokio::time::timeout(Duration::from_secs(5), handle.stop())
+            .await
+            .expect("stop should not wait for unreachable trackers");
+    }
+
+    #[test]
+    fn respect_min_interval() {
+        let response = |interval, min_interval| TrackerResponse {
+            interval,
+            min_interval,
+            ..Default::default()
+        };
+        assert_eq!(
+            (Duration::from_secs(900), Duration::ZERO),
+            next_interval(&response(900, None))
+        );
+        assert_eq!(
+            (Duration::from_secs(300), Duration::from_secs(300)),
+            next_interval(&response(60, Some(300)))
+        );
+        assert_eq!(
+            (Duration::from_secs(1800), Duration::from_secs(600)),
+            next_interval(&response(1800, Some(600)))
+        );
+        assert_eq!(
+            (Duration::from_secs(1), Duration::ZERO),
+            next_interval(&response(0, None))
+        );
+    }
+}
@@ -0,0 +1,600 @@
+//! UDP tracker protocol, as described in BEP 15.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use reqwest::Url;
+use thiserror::Error;
+use tokio::net::UdpSocket;
+
+use super::{
+    Event, Peers, Retry, ScrapeResponse, TrackerError, TrackerFailure, TrackerRequest,
+    TrackerResponse,
+};
+use crate::error::BittorrentError;
+use crate::random::Rng;
+
+/// Magic constant identifying the protocol in connect requests.
+const PROTOCOL_ID: u64 = 0x417_2710_1980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+/// How long a connection ID may be reused for. Trackers accept them for two minutes,
+/// clients are expected to stop after one.
+const CONNECTION_LIFETIME: Duration = Duration::from_secs(60);
+
+/// Most info hashes that fit in a single scrape request.
+const MAX_SCRAPE_HASHES: usize = 74;
+
+/// Largest datagram we expect from a tracker.
+const MAX_PACKET: usize = 2048;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum UdpTrackerError {
+    #[error("invalid UDP tracker URL {0}")]
+    InvalidUrl(String),
+    #[error("tracker did not respond after {0} attempts")]
+    Timeout(u32),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Connection {
+    id: u64,
+    established: Instant,
+}
+
+/// Client for UDP trackers that caches connection IDs per tracker address.
+#[derive(Debug)]
+pub struct UdpTrackerClient {
+    connections: Mutex<HashMap<SocketAddr, Connection>>,
+    /// Time to wait before the first retransmission, doubled after every attempt.
+    base_timeout: Duration,
+    /// Number of times a request is retransmitted before giving up.
+    max_retransmissions: u32,
+    /// How long a connection ID is reused for before reconnecting.
+    connection_lifetime: Duration,
+}
+
+impl Default for UdpTrackerClient {
+    /// Uses the 15 * 2^n second retransmission schedule of the specification, for n up to 8.
+    fn default() -> Self {
+        Self::new(Duration::from_secs(15), 8)
+    }
+}
+
+impl UdpTrackerClient {
+    pub fn new(base_timeout: Duration, max_retransmissions: u32) -> Self {
+        Self {
+            connections: Mutex::new(HashMap::new()),
+            base_timeout,
+            max_retransmissions,
+            connection_lifetime: CONNECTION_LIFETIME,
+        }
+    }
+
+    /// Process-wide client, so connection IDs are shared between announces.
+    pub fn shared() -> &'static Self {
+        static CLIENT: OnceLock<UdpTrackerClient> = OnceLock::new();
+        CLIENT.get_or_init(Self::default)
+    }
+
+    pub async fn announce(
+        &self,
+        url: &str,
+        info_hash: &[u8; 20],
+        request: &TrackerRequest,
+    ) -> Result<TrackerResponse, BittorrentError> {
+        let (socket, addr) = connect_socket(url).await?;
+        let mut rng = Rng::new();
+
+        let result = async {
+            let mut packet = Vec::with_capacity(98);
+            packet.extend([0; 8]); // connection id, filled in by `transact`
+            packet.extend(ACTION_ANNOUNCE.to_be_bytes());
+            packet.extend([0; 4]); // transaction id, filled in by `transact`
+            packet.extend(info_hash);
+            packet.extend(request.peer_id.as_bytes());
+            packet.extend((request.downloaded as u64).to_be_bytes());
+            packet.extend((request.left as u64).to_be_bytes());
+            packet.extend((request.uploaded as u64).to_be_bytes());
+            let event: u32 = match request.event {
+                None => 0,
+                Some(Event::Completed) => 1,
+                Some(Event::Started) => 2,
+                Some(Event::Stopped) => 3,
+            };
+            packet.extend(event.to_be_bytes());
+            // Zero means the sender's address, only IPv4 addresses fit here.
+            let ip = match request.ip {
+                Some(IpAddr::V4(ip)) => ip.octets(),
+                _ => [0; 4],
+            };
+            packet.extend(ip);
+            let key = request.key.unwrap_or_else(|| rng.next_u32());
+            packet.extend(key.to_be_bytes());
+            // -1 asks for the tracker's default.
+            let num_want = request
+                .numwant
+                .map_or(-1, |n| n.min(i32::MAX as u32) as i32);
+            packet.extend(num_want.to_be_bytes());
+            packet.extend(request.port.to_be_bytes());
+
+            let body = self
+                .transact(&socket, addr, &mut packet, ACTION_ANNOUNCE, &mut rng)
+                .await?;
+            parse_announce(&body, addr.is_ipv6())
+        }
+        .await;
+
+        if result.is_err() {
+            // The tracker may have forgotten our connection, start afresh next time.
+            self.forget(addr);
+        }
+        result
+    }
+
+    /// Asks the tracker for swarm statistics of each torrent, in batches of at most
+    /// `MAX_SCRAPE_HASHES`.
+    pub async fn scrape(
+        &self,
+        url: &str,
+        info_hashes: &[[u8; 20]],
+    ) -> Result<HashMap<[u8; 20], ScrapeResponse>, BittorrentError> {
+        let (socket, addr) = connect_socket(url).await?;
+        let mut rng = Rng::new();
+
+        let result = async {
+            let mut stats = HashMap::new();
+            for batch in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+                let mut packet = Vec::with_capacity(16 + 20 * batch.len());
+                packet.extend([0; 8]);
+                packet.extend(ACTION_SCRAPE.to_be_bytes());
+                packet.extend([0; 4]);
+                packet.extend(batch.concat());
+
+                let body = self
+                    .transact(&socket, addr, &mut packet, ACTION_SCRAPE, &mut rng)
+                    .await?;
+                if body.len() != 12 * batch.len() {
+                    return Err(TrackerError::Malformed("bad scrape response length").into());
+                }
+                for (info_hash, entry) in batch.iter().zip(body.chunks_exact(12)) {
+                    let field = |i: usize| {
+                        u32::from_be_bytes(entry[i..i + 4].try_into().expect("length 4")) as usize
+                    };
+                    stats.insert(
+                        *info_hash,
+                        ScrapeResponse {
+                            complete: field(0),
+                            downloaded: field(4),
+                            incomplete: field(8),
+                        },
+                    );
+                }
+            }
+            Ok(stats)
+        }
+        .await;
+
+        if result.is_err() {
+            self.forget(addr);
+        }
+        result
+    }
+
+    /// Returns a cached connection ID for `addr` or obtains a fresh one.
+    async fn connection_id(
+        &self,
+        socket: &UdpSocket,
+        addr: SocketAddr,
+        rng: &mut Rng,
+    ) -> Result<u64, BittorrentError> {
+        let cached = self
+            .connections
+            .lock()
+            .expect("connection cache lock poisoned")
+            .get(&addr)
+            .filter(|c| c.established.elapsed() < self.connection_lifetime)
+            .map(|c| c.id);
+        if let Some(id) = cached {
+            return Ok(id);
+        }
+
+        let mut packet = Vec::with_capacity(16);
+        packet.extend(PROTOCOL_ID.to_be_bytes());
+        packet.extend(ACTION_CONNECT.to_be_bytes());
+        packet.extend([0; 4]);
+        // Boxed since `transact` comes back here to refresh the ID of other requests.
+        let body = Box::pin(self.transact(socket, addr, &mut packet, ACTION_CONNECT, rng)).await?;
+        let id = u64::from_be_bytes(
+            body.get(..8)
+                .and_then(|b| b.try_into().ok())
+                .ok_or(TrackerError::Malformed("short connect response"))?,
+        );
+        self.connections
+            .lock()
+            .expect("connection cache lock poisoned")
+            .insert(
+                addr,
+                Connection {
+                    id,
+                    established: Instant::now(),
+                },
+            );
+        Ok(id)
+    }
+
+    fn forget(&self, addr: SocketAddr) {
+        self.connections
+            .lock()
+            .expect("connection cache lock poisoned")
+            .remove(&addr);
+    }
+
+    /// Sends `packet` with a fresh transaction ID written after its 12 byte header and waits
+    /// for the matching reply, retransmitting on the 15 * 2^n schedule.
+    ///
+    /// Requests other than connect get the connection ID for `addr` written at their start
+    /// before every attempt, so a retransmission outliving the ID reconnects first.
+    ///
+    /// Returns the reply without its action and transaction ID header.
+    async fn transact(
+        &self,
+        socket: &UdpSocket,
+        addr: SocketAddr,
+        packet: &mut [u8],
+        action: u32,
+        rng: &mut Rng,
+    ) -> Result<Vec<u8>, BittorrentError> {
+        let transaction_id = rng.next_u32();
+        packet[12..16].copy_from_slice(&transaction_id.to_be_bytes());
+
+        let mut buf = [0u8; MAX_PACKET];
+        for attempt in 0..=self.max_retransmissions {
+            if action != ACTION_CONNECT {
+                let connection_id = self.connection_id(socket, addr, rng).await?;
+                packet[..8].copy_from_slice(&connection_id.to_be_bytes());
+            }
+            socket.send(packet).await?;
+            let deadline = tokio::time::Instant::now() + self.base_timeout * 2u32.pow(attempt);
+            loop {
+                let len = match tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+                    Ok(len) => len?,
+                    Err(_) => break,
+                };
+                let reply = &buf[..len];
+                if len < 8 || reply[4..8] != transaction_id.to_be_bytes() {
+                    // A late reply to an earlier request or someone else's traffic.
+                    continue;
+                }
+                let reply_action = u32::from_be_bytes(reply[..4].try_into().expect("length 4"));
+                if reply_action == ACTION_ERROR {
+                    let message = String::from_utf8_lossy(&reply[8..]).into_owned();
+                    return Err(TrackerError::Failure(TrackerFailure {
+                        reason: message,
+                        retry: Retry::Unspecified,
+                    })
+                    .into());
+                }
+                if reply_action != action {
+                    return Err(TrackerError::Malformed("unexpected action").into());
+                }
+                return Ok(reply[8..].to_vec());
+            }
+        }
+        Err(UdpTrackerError::Timeout(self.max_retransmissions + 1).into())
+    }
+}
+
+/// Resolves the tracker's address and returns a socket connected to it.
+async fn connect_socket(url: &str) -> Result<(UdpSocket, SocketAddr), BittorrentError> {
+    let invalid = || UdpTrackerError::InvalidUrl(url.to_string());
+    let parsed = Url::parse(url).map_err(|_| invalid())?;
+    if parsed.scheme() != "udp" {
+        return Err(invalid().into());
+    }
+    let host = parsed.host_str().ok_or_else(invalid)?;
+    let port = parsed.port().ok_or_else(invalid)?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addr = tokio::net::lookup_host((host, port))
+        .await?
+        .next()
+        .ok_or_else(invalid)?;
+
+    let local: SocketAddr = if addr.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (std::net::Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(addr).await?;
+    Ok((socket, addr))
+}
+
+/// Peers are in the 18 byte IPv6 format when the announce was sent over IPv6.
+fn parse_announce(body: &[u8], ipv6: bool) -> Result<TrackerResponse, BittorrentError> {
+    let malformed = TrackerError::Malformed("bad announce response length");
+    if body.len() < 12 {
+        return Err(malformed.into());
+    }
+    let field =
+        |i: usize| u32::from_be_bytes(body[i..i + 4].try_into().expect("length 4")) as usize;
+    let peers = if ipv6 {
+        Peers::from_compact_v6(&body[12..])
+    } else {
+        Peers::from_compact_v4(&body[12..])
+    }
+    .ok_or(malformed)?;
+    Ok(TrackerResponse {
+        interval: field(0),
+        incomplete: Some(field(4)),
+        complete: Some(field(8)),
+        peers,
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::peer_id::PeerId;
+
+    /// How the fake tracker misbehaves.
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    enum Mode {
+        Normal,
+        /// Ignore the first request of every kind.
+        DropFirst,
+        /// Reply with a wrong transaction ID before the real reply.
+        WrongTransactionFirst,
+        /// Ignore the first announce only.
+        DropFirstAnnounce,
+        /// Answer announces with an error.
+        Error,
+        Silent,
+    }
+
+    struct FakeTracker {
+        url: String,
+        connects: Arc<AtomicUsize>,
+        announces: Arc<AtomicUsize>,
+    }
+
+    async fn fake_tracker(mode: Mode) -> FakeTracker {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+        let connects = Arc::new(AtomicUsize::new(0));
+        let announces = Arc::new(AtomicUsize::new(0));
+        let (c, a) = (connects.clone(), announces.clone());
+        tokio::spawn(async move {
+            let mut buf = [0u8; MAX_PACKET];
+            let mut seen = 0;
+            let mut connection_id = 0xdead_beef_u64;
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let request = &buf[..len];
+                seen += 1;
+                if mode == Mode::Silent || (mode == Mode::DropFirst && seen % 2 == 1) {
+                    continue;
+                }
+                let action = u32::from_be_bytes(request[8..12].try_into().unwrap());
+                let tid = &request[12..16];
+                let mut reply = Vec::new();
+                if action == ACTION_SCRAPE {
+                    reply.extend(ACTION_SCRAPE.to_be_bytes());
+                    reply.extend(tid);
+                    for (i, _) in request[16..].chunks(20).enumerate() {
+                        reply.extend((i as u32).to_be_bytes());
+                        reply.extend(100u32.to_be_bytes());
+                        reply.extend((2 * i as u32).to_be_bytes());
+                    }
+                } else if len == 16 {
+                    assert_eq!(PROTOCOL_ID.to_be_bytes(), request[..8]);
+                    assert_eq!(ACTION_CONNECT, action);
+                    // Every connect hands out a new ID.
+                    connection_id += c.fetch_add(1, Ordering::SeqCst) as u64;
+                    reply.extend(ACTION_CONNECT.to_be_bytes());
+                    reply.extend(tid);
+                    reply.extend(connection_id.to_be_bytes());
+                } else {
+                    assert_eq!(98, len);
+                    assert_eq!(connection_id.to_be_bytes(), request[..8]);
+                    assert_eq!(ACTION_ANNOUNCE, action);
+                    assert_eq!([7; 20], request[16..36]);
+                    assert_eq!(b"00112233445566778899", &request[36..56]);
+                    assert_eq!(2u32.to_be_bytes(), request[80..84]); // started
+                    assert_eq!(50i32.to_be_bytes(), request[92..96]);
+                    assert_eq!(6881u16.to_be_bytes(), request[96..98]);
+                    if a.fetch_add(1, Ordering::SeqCst) == 0 && mode == Mode::DropFirstAnnounce {
+                        continue;
+                    }
+                    if mode == Mode::Error {
+                        reply.extend(ACTION_ERROR.to_be_bytes());
+                        reply.extend(tid);
+                        reply.extend(b"torrent not registered");
+                    } else {
+                        reply.extend(ACTION_ANNOUNCE.to_be_bytes());
+                        reply.extend(tid);
+                        reply.extend(1800u32.to_be_bytes());
+                        reply.extend(3u32.to_be_bytes());
+                        reply.extend(5u32.to_be_bytes());
+                        reply.extend([10, 0, 0, 1, 0x1a, 0xe1]);
+                        reply.extend([10, 0, 0, 2, 0x1a, 0xe2]);
+                    }
+                }
+                if mode == Mode::WrongTransactionFirst {
+                    let mut bogus = reply.clone();
+                    bogus[4] ^= 0xff;
+                    socket.send_to(&bogus, from).await.unwrap();
+                }
+                socket.send_to(&reply, from).await.unwrap();
+            }
+        });
+        FakeTracker {
+            url,
+            connects,
+            announces,
+        }
+    }
+
+    fn client() -> UdpTrackerClient {
+        UdpTrackerClient::new(Duration::from_millis(50), 2)
+    }
+
+    fn request() -> TrackerRequest {
+        TrackerRequest {
+            event: Some(Event::Started),
+            numwant: Some(50),
+            ..TrackerRequest::new(PeerId(*b"00112233445566778899"), 6881, 100)
+        }
+    }
+
+    #[tokio::test]
+    async fn announce_returns_peers() {
+        let tracker = fake_tracker(Mode::Normal).await;
+        let response = client()
+            .announce(&tracker.url, &[7; 20], &request())
+            .await
+            .unwrap();
+        assert_eq!(1800, response.interval);
+        assert_eq!(
+            vec![
+                "10.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "10.0.0.2:6882".parse().unwrap()
+            ],
+            response.peers.0.iter().map(|p| p.addr).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn reuse_connection_id() {
+        let tracker = fake_tracker(Mode::Normal).await;
+        let client = client();
+        for _ in 0..3 {
+            client
+                .announce(&tracker.url, &[7; 20], &request())
+                .await
+                .unwrap();
+        }
+        assert_eq!(1, tracker.connects.load(Ordering::SeqCst));
+        assert_eq!(3, tracker.announces.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn retransmit_lost_requests() {
+        let tracker = fake_tracker(Mode::DropFirst).await;
+        let response = client()
+            .announce(&tracker.url, &[7; 20], &request())
+            .await
+            .unwrap();
+        assert_eq!(2, response.peers.0.len());
+    }
+
+    #[tokio::test]
+    async fn reconnect_before_retransmitting_with_expired_id() {
+        let tracker = fake_tracker(Mode::DropFirstAnnounce).await;
+        let client = UdpTrackerClient {
+            connection_lifetime: Duration::from_millis(30),
+            ..client()
+        };
+        // The fake tracker stops answering if an announce carries a stale ID.
+        let response = client
+            .announce(&tracker.url, &[7; 20], &request())
+            .await
+            .unwrap();
+        assert_eq!(1800, response.interval);
+        assert_eq!(2, tracker.connects.load(Ordering::SeqCst));
+        assert_eq!(2, tracker.announces.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn ignore_mismatched_transaction_ids() {
+        let tracker = fake_tracker(Mode::WrongTransactionFirst).await;
+        let response = client()
+            .announce(&tracker.url, &[7; 20], &request())
+            .await
+            .unwrap();
+        assert_eq!(1800, response.interval);
+    }
+
+    #[tokio::test]
+    async fn surface_tracker_errors() {
+        let tracker = fake_tracker(Mode::Error).await;
+        let client = client();
+        let error = client
+            .announce(&tracker.url, &[7; 20], &request())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            BittorrentError::TrackerError(TrackerError::Failure(TrackerFailure { ref reason, .. }))
+                if reason == "torrent not registered"
+        ));
+        // The connection is dropped after an error.
+        client
+            .announce(&tracker.url, &[7; 20], &request())
+            .await
+            .unwrap_err();
+        assert_eq!(2, tracker.connects.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn give_up_after_retransmissions() {
+        let tracker = fake_tracker(Mode::Silent).await;
+        let started = Instant::now();
+        let error = UdpTrackerClient::new(Duration::from_millis(10), 2)
+            .announce(&tracker.url, &[7; 20], &request())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            BittorrentError::UdpTrackerError(UdpTrackerError::Timeout(3))
+        ));
+        // 10 + 20 + 40 milliseconds.
+        assert!(started.elapsed() >= Duration::from_millis(70));
+    }
+
+    #[tokio::test]
+    async fn scrape_in_batches() {
+        let tracker = fake_tracker(Mode::Normal).await;
+        let hashes: Vec<[u8; 20]> = (0..100u8).map(|i| [i; 20]).collect();
+        let client = client();
+        let stats = client.scrape(&tracker.url, &hashes).await.unwrap();
+        assert_eq!(100, stats.len());
+        assert_eq!(
+            ScrapeResponse {
+                complete: 3,
+                downloaded: 100,
+                incomplete: 6,
+            },
+            stats[&[3; 20]]
+        );
+        // The second batch starts counting from zero again.
+        assert_eq!(0, stats[&[74; 20]].complete);
+        assert_eq!(1, tracker.connects.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn reject_non_udp_urls() {
+        for url in [
+            "http://example.com/announce",
+            "udp://example.com",
+            "nonsense",
+        ] {
+            assert!(matches!(
+                client().announce(url, &[7; 20], &request()).await,
+                Err(BittorrentError::UdpTrackerError(
+                    UdpTrackerError::InvalidUrl(_)
+                ))
+            ));
+        }
+    }
+}
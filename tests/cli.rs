@@ -0,0 +1,50 @@
+//! Runs the command line tool the way users do.
+
+use std::fs;
+use std::net::TcpListener;
+use std::process::Command;
+
+/// A single piece torrent announcing to `tracker`.
+fn torrent(tracker: &str) -> Vec<u8> {
+    let mut torrent = format!("d8:announce{}:{tracker}4:infod", tracker.len()).into_bytes();
+    torrent.extend(b"6:lengthi100e4:name8:file.bin12:piece lengthi32768e6:pieces20:");
+    torrent.extend([0xab; 20]);
+    torrent.extend(b"ee");
+    torrent
+}
+
+#[test]
+fn fail_downloads_without_peers() {
+    // Nothing listens on the port once the listener is gone, so every announce fails.
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let dir = tempfile::tempdir().unwrap();
+    let torrent_path = dir.path().join("file.torrent");
+    fs::write(
+        &torrent_path,
+        torrent(&format!("http://127.0.0.1:{port}/announce")),
+    )
+    .unwrap();
+    let output = dir.path().join("out");
+
+    let result = Command::new(env!("CARGO_BIN_EXE_bittorrent"))
+        .arg("download")
+        .arg("-o")
+        .arg(&output)
+        .arg(&torrent_path)
+        .args(["--stall_timeout", "1"])
+        .output()
+        .unwrap();
+
+    assert!(!result.status.success());
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(stderr.contains("download file.bin"), "{stderr}");
+    assert!(stderr.contains("no peer connected"), "{stderr}");
+    assert!(
+        !output.exists(),
+        "an aborted download should not leave files behind"
+    );
+}
@@ -0,0 +1,122 @@
+//! Allocation counting for the message decoder, kept in its own test binary so that no other
+//! test runs under the counting allocator.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+use bittorrent::peer::{MessageFramer, PeerMessage};
+use bytes::{Bytes, BytesMut};
+use futures_util::StreamExt;
+use tokio_util::codec::{Encoder, FramedRead};
+
+/// Counts allocations per thread, so benchmarks aren't disturbed by tests running alongside.
+struct CountingAllocator;
+
+thread_local! {
+    /// Number of allocations and bytes allocated on this thread.
+    static ALLOCATED: Cell<(usize, usize)> = const { Cell::new((0, 0)) };
+}
+
+fn count(size: usize) {
+    let _ = ALLOCATED.try_with(|allocated| {
+        let (count, bytes) = allocated.get();
+        allocated.set((count + 1, bytes + size));
+    });
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count(new_size);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// What decoding a run of blocks took.
+struct Decoded {
+    elapsed: Duration,
+    /// Allocations and bytes allocated per block.
+    allocations: f64,
+    bytes: f64,
+}
+
+/// Decodes `blocks` 16 KiB blocks read from memory.
+async fn decode_blocks(blocks: u32) -> Decoded {
+    let block = Bytes::from(vec![0xab; 1 << 14]);
+    let mut framer = MessageFramer::for_torrent(blocks as usize, block.len());
+    let mut wire = BytesMut::new();
+    for index in 0..blocks {
+        let piece = PeerMessage::Piece {
+            index,
+            begin: 0,
+            block: block.clone(),
+        };
+        framer.encode(piece, &mut wire).unwrap();
+    }
+
+    let (count_before, bytes_before) = ALLOCATED.get();
+    let start = Instant::now();
+    let mut messages = FramedRead::new(&wire[..], framer);
+    let mut received = 0;
+    while let Some(message) = messages.next().await {
+        let PeerMessage::Piece { block, .. } = message.unwrap() else {
+            panic!("expected a piece");
+        };
+        received += block.len();
+    }
+    let elapsed = start.elapsed();
+    let (count_after, bytes_after) = ALLOCATED.get();
+
+    assert_eq!(blocks as usize * block.len(), received);
+    Decoded {
+        elapsed,
+        allocations: (count_after - count_before) as f64 / blocks as f64,
+        bytes: (bytes_after - bytes_before) as f64 / blocks as f64,
+    }
+}
+
+/// Blocks are split off the read buffer rather than copied, so decoding them hardly
+/// allocates at all. Copying would take an allocation of 16 KiB per block.
+#[tokio::test]
+async fn decode_pieces_without_copying() {
+    let decoded = decode_blocks(256).await;
+    assert!(
+        decoded.allocations < 0.5,
+        "{:.2} allocations per block",
+        decoded.allocations
+    );
+    assert!(
+        decoded.bytes < 1024.0,
+        "{:.0} bytes allocated per block",
+        decoded.bytes
+    );
+}
+
+/// Decodes 64 MiB of 16 KiB blocks, reporting throughput and allocations.
+/// Run with `cargo test --release --test decode_pieces -- --ignored --nocapture`.
+#[tokio::test]
+#[ignore]
+async fn bench_decode_pieces() {
+    const BLOCKS: u32 = 4096;
+    let decoded = decode_blocks(BLOCKS).await;
+    println!(
+        "{} MiB in {:?}, {:.0} MiB/s, {:.2} allocations and {:.0} bytes allocated per block",
+        BLOCKS / 64,
+        decoded.elapsed,
+        (BLOCKS / 64) as f64 / decoded.elapsed.as_secs_f64(),
+        decoded.allocations,
+        decoded.bytes,
+    );
+}